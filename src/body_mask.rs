@@ -0,0 +1,137 @@
+use crate::filter::OUTSIDE;
+use ndarray::{Array2, Array3, ArrayViewMut2, Axis, Zip};
+
+/// 体の輪郭を求めるときのCT値のしきい値（HU）
+/// 空気（-1000）と軟部組織（0前後）の間の値
+pub const BODY_THRESHOLD: i16 = -500;
+
+/// 体の輪郭の内側を`true`とするマスクを求める
+///
+/// 1. CT値が`threshold`より大きい画素を選ぶ
+/// 2. 6近傍で繋がっている最も大きな塊だけを残し、寝台などを取り除く
+/// 3. スライスごとに、外側と繋がっていない穴（肺や気管）を埋める
+pub fn body_mask(data: &Array3<i16>, threshold: i16) -> Array3<bool> {
+  let mask = data.mapv(|v| v > threshold);
+  let mut mask = largest_component(&mask);
+  for slice in mask.axis_iter_mut(Axis(0)) {
+    fill_holes(slice);
+  }
+  mask
+}
+
+/// 6近傍で繋がっている`true`の塊のうち、最も大きなものだけを残す
+fn largest_component(mask: &Array3<bool>) -> Array3<bool> {
+  let (height, rows, columns) = mask.dim();
+  // 0はまだ調べていない画素、それ以外は塊の番号
+  let mut component = Array3::<u32>::zeros(mask.dim());
+  let mut largest = (0, 0);
+  let mut stack = Vec::new();
+  let mut id = 0;
+  for ((z, y, x), inside) in mask.indexed_iter() {
+    if !*inside || component[[z, y, x]] != 0 {
+      continue;
+    }
+    id += 1;
+    let mut size = 0;
+    component[[z, y, x]] = id;
+    stack.push([z, y, x]);
+    while let Some([z, y, x]) = stack.pop() {
+      size += 1;
+      let neighbors = [
+        (z > 0).then(|| [z - 1, y, x]),
+        (z + 1 < height).then(|| [z + 1, y, x]),
+        (y > 0).then(|| [z, y - 1, x]),
+        (y + 1 < rows).then(|| [z, y + 1, x]),
+        (x > 0).then(|| [z, y, x - 1]),
+        (x + 1 < columns).then(|| [z, y, x + 1]),
+      ];
+      for p in neighbors.into_iter().flatten() {
+        if mask[p] && component[p] == 0 {
+          component[p] = id;
+          stack.push(p);
+        }
+      }
+    }
+    if size > largest.1 {
+      largest = (id, size);
+    }
+  }
+  component.mapv(|c| c != 0 && c == largest.0)
+}
+
+/// 1枚のスライスの中で、画像の端と4近傍で繋がっていない`false`の画素を`true`にする
+fn fill_holes(mut slice: ArrayViewMut2<'_, bool>) {
+  let (rows, columns) = slice.dim();
+  let mut outside = Array2::from_elem((rows, columns), false);
+  let mut stack = Vec::new();
+  for y in 0..rows {
+    for x in 0..columns {
+      let border = y == 0 || x == 0 || y + 1 == rows || x + 1 == columns;
+      if border && !slice[[y, x]] {
+        outside[[y, x]] = true;
+        stack.push([y, x]);
+      }
+    }
+  }
+  while let Some([y, x]) = stack.pop() {
+    let neighbors = [
+      (y > 0).then(|| [y - 1, x]),
+      (y + 1 < rows).then(|| [y + 1, x]),
+      (x > 0).then(|| [y, x - 1]),
+      (x + 1 < columns).then(|| [y, x + 1]),
+    ];
+    for p in neighbors.into_iter().flatten() {
+      if !slice[p] && !outside[p] {
+        outside[p] = true;
+        stack.push(p);
+      }
+    }
+  }
+  Zip::from(&mut slice)
+    .and(&outside)
+    .for_each(|inside, outside| *inside = !*outside);
+}
+
+/// マスクの外側の画素のラベルを`OUTSIDE`にする
+pub fn apply_mask(labels: &mut Array3<u8>, mask: &Array3<bool>) {
+  labels.zip_mut_with(mask, |label, inside| {
+    if !*inside {
+      *label = OUTSIDE;
+    }
+  });
+}
+
+#[cfg(test)]
+mod body_mask_test {
+  use crate::body_mask::*;
+
+  #[test]
+  fn check_body_mask() {
+    // 体（0）の中に肺（-800）があり、離れた場所に寝台（100）がある
+    let mut data = Array3::from_elem((2, 9, 9), -1000i16);
+    for ((_, y, x), v) in data.indexed_iter_mut() {
+      if (1..6).contains(&y) && (1..8).contains(&x) {
+        *v = if (2..5).contains(&y) && (3..6).contains(&x) {
+          -800
+        } else {
+          0
+        };
+      }
+      if y == 7 {
+        *v = 100;
+      }
+    }
+    let mask = body_mask(&data, BODY_THRESHOLD);
+    for ((_, y, x), inside) in mask.indexed_iter() {
+      assert_eq!(*inside, (1..6).contains(&y) && (1..8).contains(&x));
+    }
+  }
+
+  #[test]
+  fn check_apply_mask() {
+    let mut labels = Array3::from_elem((1, 1, 3), 0u8);
+    let mask = Array3::from_shape_vec((1, 1, 3), vec![true, false, true]).unwrap();
+    apply_mask(&mut labels, &mask);
+    assert_eq!(labels.into_raw_vec(), vec![0, OUTSIDE, 0]);
+  }
+}
@@ -0,0 +1,151 @@
+use crate::k_means::Summary;
+use crate::{Center, Point};
+
+/// CT値と位置を合わせて測った重心との距離
+///
+/// 位置は`dim`（x, y, zの順の大きさ）で0から1に正規化し、軸ごとに`weight`倍してCT値と同じ単位で扱う
+/// 重心の位置がまだ決まっていない場合はCT値だけで測る
+pub fn calc_distance(
+  center: &Center,
+  point: Point,
+  data: i16,
+  dim: [usize; 3],
+  weight: [f64; 3],
+) -> usize {
+  let d = center.data as f64 - data as f64;
+  let mut sum = d * d;
+  if let Some(p) = center.point {
+    let delta = [
+      p.x as f64 - point.x as f64,
+      p.y as f64 - point.y as f64,
+      p.z as f64 - point.z as f64,
+    ];
+    for axis in 0..3 {
+      let d = delta[axis] / dim[axis].saturating_sub(1).max(1) as f64 * weight[axis];
+      sum += d * d;
+    }
+  }
+  sum.sqrt().round() as usize
+}
+
+/// CT値だけで測った重心との距離
+pub fn calc_intensity_distance(center: &Center, data: i16) -> usize {
+  center.data.abs_diff(data) as usize
+}
+
+/// グループに属する画素のCT値の平均を重心とする
+pub fn calc_center(summary: &Summary) -> Option<Center> {
+  if summary.count == 0 {
+    None
+  } else {
+    let d = (summary.sum / summary.count as i64) as i16;
+    Some(Center {
+      point: None,
+      data: d,
+    })
+  }
+}
+
+/// グループに属する画素のCT値と位置の平均を重心とする
+pub fn calc_spatial_center(summary: &Summary) -> Option<Center> {
+  let center = calc_center(summary)?;
+  let [x, y, z] = summary.sum_point.map(|v| (v / summary.count as u64) as u16);
+  Some(Center {
+    point: Some(Point::new(x, y, z)),
+    ..center
+  })
+}
+
+#[cfg(test)]
+mod cluster_test {
+  use crate::cluster::*;
+  use crate::k_means::{self, Options, Solution};
+  use ndarray::Array3;
+
+  const OPTIONS: Options = Options {
+    tolerance: 0.0,
+    max_iterations: 100,
+  };
+
+  /// 同じCT値（100）の2つの塊が空気（-1000）を挟んで離れている
+  fn two_blobs() -> (Array3<i16>, Vec<Center>) {
+    let data = Array3::from_shape_fn(
+      (1, 1, 10),
+      |(_, _, x)| {
+        if !(2..8).contains(&x) {
+          100
+        } else {
+          -1000
+        }
+      },
+    );
+    let init = vec![
+      Center {
+        point: Some(Point::new(5, 0, 0)),
+        data: -1000,
+      },
+      Center {
+        point: Some(Point::new(0, 0, 0)),
+        data: 100,
+      },
+      Center {
+        point: Some(Point::new(9, 0, 0)),
+        data: 100,
+      },
+    ];
+    (data, init)
+  }
+
+  async fn solve_spatial(data: &Array3<i16>, init: Vec<Center>, weight: f64) -> Solution<Center> {
+    let dim = [10, 1, 1];
+    let weight = [weight; 3];
+    k_means::solve(
+      |center: &Center, point, data| calc_distance(center, point, data, dim, weight),
+      calc_spatial_center,
+      |c1: &Center, c2: &Center| match c2.point {
+        Some(point) => calc_distance(c1, point, c2.data, dim, weight) as f64,
+        None => calc_intensity_distance(c1, c2.data) as f64,
+      },
+      init,
+      data,
+      None,
+      OPTIONS,
+    )
+    .await
+  }
+
+  #[tokio::test]
+  async fn check_spatial_weight() {
+    let (data, init) = two_blobs();
+    let solution = solve_spatial(&data, init, 900.0).await;
+    assert!(solution.converged);
+    assert_eq!(
+      solution.labels.into_raw_vec(),
+      vec![1, 1, 0, 0, 0, 0, 0, 0, 2, 2]
+    );
+    assert_eq!(solution.centers[1].point, Some(Point::new(0, 0, 0)));
+    assert_eq!(solution.centers[2].point, Some(Point::new(8, 0, 0)));
+  }
+
+  #[tokio::test]
+  async fn check_spatial_weight_zero() {
+    // 重みが0ならCT値だけで分けた結果と同じになる
+    let (data, init) = two_blobs();
+    let spatial = solve_spatial(&data, init.clone(), 0.0).await;
+    let intensity = k_means::solve_histogram(
+      calc_intensity_distance,
+      calc_center,
+      |c1: &Center, c2: &Center| calc_intensity_distance(c1, c2.data) as f64,
+      init,
+      &data,
+      None,
+      OPTIONS,
+    )
+    .await;
+    assert_eq!(spatial.labels, intensity.labels);
+    assert_eq!(
+      spatial.labels.into_raw_vec(),
+      vec![1, 1, 0, 0, 0, 0, 0, 0, 1, 1]
+    );
+  }
+}
@@ -1,48 +1,19 @@
 use crate::Point;
-use tokio_stream::StreamExt;
+use ndarray::{Array3, Zip};
 use tracing::*;
 
-pub type GroupList = (Point, Vec<usize>);
-pub type Block<T> = Vec<Vec<Vec<Option<T>>>>;
+/// どのグループにも属していない画素のラベル
+pub const NO_GROUP: u8 = u8::MAX;
 
-/// pointのリストから、どのグループに属しているのかのデータを生成するようにした
-/// オープニングクロージングの過程でグループが複数個ありえるため、リストにしている
-pub fn gen_blocks(
-  rows: usize,
-  columns: usize,
-  height: usize,
-  data: &[Vec<Point>],
-) -> Block<GroupList> {
-  let mut v = vec![vec![vec![None; rows]; columns]; height];
-  for (n, lst) in data.iter().enumerate() {
-    for point in lst.iter() {
-      v[point.z as usize][point.y as usize][point.x as usize] = Some((*point, vec![n]));
-    }
-  }
-  v
-}
+/// 体の外側として解析から除いた画素のラベル
+pub const OUTSIDE: u8 = u8::MAX - 1;
 
-#[allow(dead_code)]
-pub async fn blocks_to_points(blocks: Block<GroupList>, group_size: usize) -> Vec<Vec<Point>> {
-  let mut v = vec![Vec::new(); group_size];
-  let mut xy_stream = tokio_stream::iter(blocks);
-  while let Some(xy) = xy_stream.next().await {
-    let mut x_stream = tokio_stream::iter(xy);
-    while let Some(x) = x_stream.next().await {
-      let mut group_stream = tokio_stream::iter(x);
-      while let Some(grouplit_opt) = group_stream.next().await {
-        if let Some((point, group)) = grouplit_opt {
-          let mut group = group.clone();
-          group.sort();
-          if let Some(g) = group.first() {
-            v[*g].push(point);
-          }
-        }
-      }
-    }
-  }
-  v
-}
+/// 画素が属しうるグループの集合
+/// `i`番目のビットが立っていればグループ`i`を含む
+type Groups = u32;
+
+/// ノイズ除去で扱えるグループの数の上限
+pub const MAX_GROUPS: usize = Groups::BITS as usize;
 
 /// 境界チェックをした上で近傍のリストを生成
 /// 一旦周囲6近傍で
@@ -54,7 +25,7 @@ pub fn neighborhood(rows: usize, columns: usize, height: usize, point: &Point) -
       ..*point
     });
   }
-  if (point.x as usize) < rows - 1 {
+  if (point.x as usize) < columns - 1 {
     v.push(Point {
       x: point.x + 1,
       ..*point
@@ -67,7 +38,7 @@ pub fn neighborhood(rows: usize, columns: usize, height: usize, point: &Point) -
       ..*point
     });
   }
-  if (point.y as usize) < columns - 1 {
+  if (point.y as usize) < rows - 1 {
     v.push(Point {
       y: point.y + 1,
       ..*point
@@ -90,151 +61,161 @@ pub fn neighborhood(rows: usize, columns: usize, height: usize, point: &Point) -
   v
 }
 
+/// ラベルをそのグループだけを含む集合に直す
+/// `NO_GROUP`と`OUTSIDE`は空集合とする
+fn to_groups(labels: &Array3<u8>) -> Array3<Groups> {
+  labels.mapv(|l| if (l as usize) < MAX_GROUPS { 1 << l } else { 0 })
+}
+
+/// 集合の中で番号が最も小さいグループをラベルとする
+/// 空集合は`NO_GROUP`とし、元のラベルが`OUTSIDE`か`NO_GROUP`の画素はそのまま残す
+fn to_labels(groups: &Array3<Groups>, labels: &Array3<u8>) -> Array3<u8> {
+  Zip::from(groups).and(labels).map_collect(|g, l| {
+    if *l == OUTSIDE || *l == NO_GROUP {
+      *l
+    } else if *g == 0 {
+      NO_GROUP
+    } else {
+      g.trailing_zeros() as u8
+    }
+  })
+}
+
+/// 周囲6近傍のグループの集合のリスト
+fn neighbor_groups(groups: &Array3<Groups>, z: usize, y: usize, x: usize) -> Vec<Groups> {
+  let (height, rows, columns) = groups.dim();
+  neighborhood(
+    rows,
+    columns,
+    height,
+    &Point::new(x as u16, y as u16, z as u16),
+  )
+  .iter()
+  .map(|p| groups[[p.z as usize, p.y as usize, p.x as usize]])
+  .collect()
+}
+
 /// 3次元での膨張処理
 /// 周囲6近傍のグループの和集合
 /// 周囲26近傍まで伸ばすかは要検討
-pub async fn diation_block(
-  rows: usize,
-  columns: usize,
-  height: usize,
-  data: &Block<GroupList>,
-) -> Block<GroupList> {
-  let mut v = vec![vec![vec![None; rows]; columns]; height];
-  let mut xy_stream = tokio_stream::iter(data.clone());
-  while let Some(xy) = xy_stream.next().await {
-    let mut x_stream = tokio_stream::iter(xy);
-    while let Some(x_data) = x_stream.next().await {
-      let mut stream = tokio_stream::iter(x_data);
-      while let Some(grouplit_opt) = stream.next().await {
-        if let Some((point, _)) = grouplit_opt {
-          // 和集合を取る
-          let mut group = neighborhood(rows, columns, height, &point)
-            .iter()
-            .map(|p| {
-              if let Some((_, lst)) = &data[p.z as usize][p.y as usize][p.x as usize] {
-                lst.clone()
-              } else {
-                Vec::new()
-              }
-            })
-            .collect::<Vec<Vec<_>>>()
-            .concat();
-          group.sort();
-          group.dedup();
-          v[point.z as usize][point.y as usize][point.x as usize] = Some((point, group));
-        }
-      }
-    }
+async fn diation_block(groups: &Array3<Groups>) -> Array3<Groups> {
+  let mut v = Array3::zeros(groups.dim());
+  for ((z, y, x), group) in v.indexed_iter_mut() {
+    *group = neighbor_groups(groups, z, y, x)
+      .into_iter()
+      .fold(0, |a, g| a | g);
   }
   v
 }
 
 /// 3次元での収縮処理
-/// 周囲6近傍のグループの積集合
+/// 周囲6近傍のうち空でないグループの積集合
+/// 空でない近傍が無ければ空集合とする
 /// 周囲26近傍まで伸ばすかは要検討
-pub async fn erosion_block(
-  rows: usize,
-  columns: usize,
-  height: usize,
-  data: &Block<GroupList>,
-  group_size: usize,
-) -> Block<GroupList> {
-  let mut v = vec![vec![vec![None; rows]; columns]; height];
-  let mut xy_stream = tokio_stream::iter(data.clone());
-  while let Some(xy) = xy_stream.next().await {
-    let mut x_stream = tokio_stream::iter(xy);
-    while let Some(x_data) = x_stream.next().await {
-      let mut stream = tokio_stream::iter(x_data);
-      while let Some(grouplit_opt) = stream.next().await {
-        if let Some((point, _)) = grouplit_opt {
-          // 積集合を取る
-          let group_lst = neighborhood(rows, columns, height, &point)
-            .iter()
-            .map(|p| {
-              if let Some((_, lst)) = &data[p.z as usize][p.y as usize][p.x as usize] {
-                lst.clone()
-              } else {
-                Vec::new()
-              }
-            })
-            .collect::<Vec<Vec<_>>>();
-          let mut group = Vec::new();
-          for n in 0..group_size {
-            if group_lst
-              .iter()
-              .filter(|g| !g.is_empty())
-              .all(|g| g.iter().any(|n2| n == *n2))
-            {
-              group.push(n);
-            }
-          }
-          group.sort();
-          v[point.z as usize][point.y as usize][point.x as usize] = Some((point, group));
-        }
-      }
-    }
+async fn erosion_block(groups: &Array3<Groups>) -> Array3<Groups> {
+  let mut v = Array3::zeros(groups.dim());
+  for ((z, y, x), group) in v.indexed_iter_mut() {
+    *group = neighbor_groups(groups, z, y, x)
+      .into_iter()
+      .filter(|g| *g != 0)
+      .reduce(|a, g| a & g)
+      .unwrap_or(0);
   }
   v
 }
 
 /// 同じ回数分だけ収縮して膨張する
-#[allow(dead_code)]
-pub async fn opening_block(
-  rows: usize,
-  columns: usize,
-  height: usize,
-  data: &Block<GroupList>,
-  group_size: usize,
-  n: usize,
-) -> Block<GroupList> {
+/// 途中ではグループの集合を保ち、最後に番号が最も小さいグループをラベルとする
+pub async fn opening_block(labels: &Array3<u8>, n: usize) -> Array3<u8> {
   info!("[START] opening block");
-  let mut v = data.to_vec();
+  let mut v = to_groups(labels);
   for i in 0..n {
     info!("[START] erosion({i})");
-    v = erosion_block(rows, columns, height, &v, group_size).await;
+    v = erosion_block(&v).await;
     info!("[END] erosion({i})");
   }
   for i in 0..n {
     info!("[START] diation({i})");
-    v = diation_block(rows, columns, height, &v).await;
+    v = diation_block(&v).await;
     info!("[END] diation({i})");
   }
   info!("[END] opening block");
-  v
+  to_labels(&v, labels)
 }
 
 /// 同じ回数分だけ膨張して収縮する
-#[allow(dead_code)]
-pub async fn closing_block(
-  rows: usize,
-  columns: usize,
-  height: usize,
-  data: &Block<GroupList>,
-  group_size: usize,
-  n: usize,
-) -> Block<GroupList> {
+/// 途中ではグループの集合を保ち、最後に番号が最も小さいグループをラベルとする
+pub async fn closing_block(labels: &Array3<u8>, n: usize) -> Array3<u8> {
   info!("[START] closing block");
-  let mut v = data.to_vec();
+  let mut v = to_groups(labels);
   for i in 0..n {
     info!("[START] diation({i})");
-    v = diation_block(rows, columns, height, &v).await;
+    v = diation_block(&v).await;
     info!("[END] diation({i})");
   }
   for i in 0..n {
     info!("[START] erosion({i})");
-    v = erosion_block(rows, columns, height, &v, group_size).await;
+    v = erosion_block(&v).await;
     info!("[END] erosion({i})");
   }
   info!("[END] closing block");
-  v
+  to_labels(&v, labels)
+}
+
+/// 1つのグループだけを`n`回ずつオープニング・クロージングし直す
+///
+/// `raw`はノイズ除去をする前のラベル、`labels`は全体をノイズ除去した後のラベル
+/// そのグループとそれ以外の2つに分けて処理し、結果で`labels`の中のそのグループを置き換える
+/// `raw`で`NO_GROUP`か`OUTSIDE`の画素はどちらにも入れず、そのグループにはしない
+pub async fn refilter_group(raw: &Array3<u8>, labels: &mut Array3<u8>, group: u8, n: usize) {
+  let binary = raw.mapv(|l| match l {
+    _ if l == group => 0,
+    NO_GROUP | OUTSIDE => l,
+    _ => 1,
+  });
+  let binary = opening_block(&binary, n).await;
+  let binary = closing_block(&binary, n).await;
+  labels.zip_mut_with(&binary, |label, b| {
+    if *b == 0 {
+      *label = group;
+    } else if *label == group {
+      *label = NO_GROUP;
+    }
+  });
 }
 
 #[cfg(test)]
 mod block_test {
   use crate::filter::*;
   use crate::Point;
+  use ndarray::{Array3, Zip};
+
+  /// pointのリストから、どのグループに属しているのかを表すラベルを生成する
+  /// ラベルは`[[z, y, x]]`で添字を与える
+  /// `rows`はy方向の大きさ、`columns`はx方向の大きさ
+  fn gen_labels(rows: usize, columns: usize, height: usize, data: &[Vec<Point>]) -> Array3<u8> {
+    let mut v = Array3::from_elem((height, rows, columns), NO_GROUP);
+    for (n, lst) in data.iter().enumerate() {
+      for point in lst.iter() {
+        v[[point.z as usize, point.y as usize, point.x as usize]] = n as u8;
+      }
+    }
+    v
+  }
+
+  /// ラベルからグループごとのpointのリストを生成する
+  fn labels_to_points(labels: &Array3<u8>, group_size: usize) -> Vec<Vec<Point>> {
+    let mut v = vec![Vec::new(); group_size];
+    for ((z, y, x), label) in labels.indexed_iter() {
+      if let Some(lst) = v.get_mut(*label as usize) {
+        lst.push(Point::new(x as u16, y as u16, z as u16));
+      }
+    }
+    v
+  }
+
   #[test]
-  fn check_gen_blocks() {
+  fn check_gen_labels() {
     let data = vec![
       vec![Point::new(2, 2, 2), Point::new(2, 2, 3)],
       vec![Point::new(2, 3, 2)],
@@ -242,69 +223,36 @@ mod block_test {
     let rows = 4;
     let columns = 4;
     let height = 5;
-    let gen_blocks = gen_blocks(rows, columns, height, &data);
-    let blocks = vec![
-      // z == 0
-      vec![vec![None; columns]; rows],
-      // z == 1
-      vec![vec![None; columns]; rows],
-      // z == 2
-      vec![
-        // y == 0
-        vec![None; columns],
-        // y == 1
-        vec![None; columns],
-        // y == 2
-        vec![
-          // x == 0
-          None,
-          // x == 1
-          None,
-          // x == 2
-          Some((Point::new(2, 2, 2), vec![0])),
-          // x == 3
-          None,
-        ],
-        // y == 3
-        vec![
-          // x == 0
-          None,
-          // x == 1
-          None,
-          // x == 2
-          Some((Point::new(2, 3, 2), vec![1])),
-          // x == 3
-          None,
-        ],
-      ],
-      // z == 3
-      vec![
-        // y == 0
-        vec![None; columns],
-        // y == 1
-        vec![None; columns],
-        // y == 2
-        vec![
-          // x == 0
-          None,
-          // x == 1
-          None,
-          // x == 2
-          Some((Point::new(2, 2, 3), vec![0])),
-          // x == 3
-          None,
-        ],
-        // y == 3
-        vec![None; columns],
-      ],
-      // z == 4
-      vec![vec![None; columns]; rows],
-    ];
-    assert_eq!(gen_blocks, blocks);
+    let labels = gen_labels(rows, columns, height, &data);
+    let mut expectation = Array3::from_elem((height, rows, columns), NO_GROUP);
+    expectation[[2, 2, 2]] = 0;
+    expectation[[3, 2, 2]] = 0;
+    expectation[[2, 3, 2]] = 1;
+    assert_eq!(labels, expectation);
   }
 
-  #[tokio::test]
-  async fn check_blocks_to_points_1() {
+  #[test]
+  fn check_gen_labels_non_square() {
+    let data = vec![vec![Point::new(2, 1, 0)]];
+    let rows = 2;
+    let columns = 3;
+    let height = 1;
+    let labels = gen_labels(rows, columns, height, &data);
+    let expectation = Array3::from_shape_vec(
+      (height, rows, columns),
+      vec![NO_GROUP, NO_GROUP, NO_GROUP, NO_GROUP, NO_GROUP, 0],
+    )
+    .unwrap();
+    assert_eq!(labels, expectation);
+    let mut gen = neighborhood(rows, columns, height, &Point::new(2, 1, 0));
+    let mut expectation = vec![Point::new(1, 1, 0), Point::new(2, 0, 0)];
+    gen.sort();
+    expectation.sort();
+    assert_eq!(gen, expectation);
+  }
+
+  #[test]
+  fn check_labels_to_points_1() {
     let data = vec![
       vec![Point::new(2, 2, 2), Point::new(2, 2, 3)],
       vec![Point::new(2, 3, 2)],
@@ -313,32 +261,78 @@ mod block_test {
     let columns = 4;
     let height = 5;
     let group_size = 2;
-    let gen = blocks_to_points(gen_blocks(rows, columns, height, &data), group_size).await;
+    let gen = labels_to_points(&gen_labels(rows, columns, height, &data), group_size);
     assert_eq!(gen, data);
   }
 
-  #[tokio::test]
-  async fn check_blocks_to_points_2() {
-    let data = vec![vec![Point::new(0, 0, 0)]];
-    let group_size = 1;
-    let gen = blocks_to_points(
-      vec![vec![vec![Some((Point::new(0, 0, 0), vec![0]))]]],
-      group_size,
-    )
-    .await;
+  #[test]
+  fn check_labels_to_points_2() {
+    let data = vec![vec![], vec![Point::new(0, 0, 0)]];
+    let group_size = 2;
+    let gen = labels_to_points(&Array3::from_elem((1, 1, 1), 1), group_size);
     assert_eq!(gen, data);
   }
 
   #[tokio::test]
-  async fn check_blocks_to_points_3() {
-    let data = vec![vec![], vec![Point::new(0, 0, 0)]];
-    let group_size = 2;
-    let gen = blocks_to_points(
-      vec![vec![vec![Some((Point::new(0, 0, 0), vec![1]))]]],
-      group_size,
-    )
-    .await;
-    assert_eq!(gen, data);
+  async fn check_opening_block() {
+    // 3x3x3の塊と孤立した1画素
+    let mut labels = Array3::from_elem((5, 5, 5), 0);
+    for z in 1..4 {
+      for y in 1..4 {
+        for x in 1..4 {
+          labels[[z, y, x]] = 1;
+        }
+      }
+    }
+    labels[[0, 0, 4]] = 2;
+    let eroded = erosion_block(&to_groups(&labels)).await;
+    let dilated = to_labels(&diation_block(&eroded).await, &labels);
+    let eroded = to_labels(&eroded, &labels);
+    assert_eq!(eroded[[2, 2, 2]], 1);
+    assert_eq!(eroded[[1, 1, 1]], NO_GROUP);
+    assert_eq!(eroded[[0, 0, 4]], 0);
+    assert_eq!(dilated[[1, 2, 2]], 1);
+    let opened = opening_block(&labels, 1).await;
+    assert_ne!(opened[[0, 0, 4]], 2);
+  }
+
+  #[tokio::test]
+  async fn check_no_group_kept() {
+    // どのグループにも属していない領域はオープニング・クロージングの後も属さない
+    let mut labels = Array3::from_elem((5, 5, 5), NO_GROUP);
+    labels[[0, 0, 0]] = 1;
+    for filtered in [
+      opening_block(&labels, 1).await,
+      closing_block(&labels, 1).await,
+    ] {
+      assert_eq!(filtered[[2, 2, 2]], NO_GROUP);
+      assert!(Zip::from(&filtered)
+        .and(&labels)
+        .all(|f, l| *l != NO_GROUP || *f == NO_GROUP));
+    }
+  }
+
+  #[tokio::test]
+  async fn check_closing_block() {
+    // 2つのグループの境界は変わらない
+    let labels = Array3::from_shape_vec((1, 1, 6), vec![1, 1, 1, 2, 2, 2]).unwrap();
+    assert_eq!(closing_block(&labels, 1).await, labels);
+    let mut labels = labels;
+    labels[[0, 0, 0]] = OUTSIDE;
+    assert_eq!(closing_block(&labels, 1).await, labels);
+  }
+
+  #[tokio::test]
+  async fn check_refilter_group() {
+    // グループ2の孤立した1画素だけを取り除く
+    let mut raw = Array3::from_elem((3, 3, 3), 0);
+    raw[[1, 1, 1]] = 2;
+    raw[[0, 0, 0]] = 1;
+    let mut labels = raw.clone();
+    refilter_group(&raw, &mut labels, 2, 1).await;
+    assert_ne!(labels[[1, 1, 1]], 2);
+    assert_eq!(labels[[0, 0, 0]], 1);
+    assert!(labels.iter().all(|l| *l != 2));
   }
 
   #[test]
@@ -393,93 +387,3 @@ mod block_test {
     assert_eq!(gen, expectation);
   }
 }
-
-/// 膨張
-/// 周辺8近傍の中に一つでも塗られていたら塗る
-pub fn diation(rows: i16, columns: i16, z: u16, data: &[Point]) -> Vec<Point> {
-  let mut v = Vec::new();
-  for x in 0..rows {
-    for y in 0..columns {
-      let point_lst = [
-        (x - 1, y, z),
-        (x + 1, y, z),
-        (x, y - 1, z),
-        (x, y + 1, z),
-        (x - 1, y - 1, z),
-        (x + 1, y + 1, z),
-        (x - 1, y + 1, z),
-        (x + 1, y - 1, z),
-      ]
-      .iter()
-      .filter(|(x, y, _)| *x >= 0 && *y >= 0)
-      .map(|(x, y, z)| Point::new(*x as u16, *y as u16, *z))
-      .collect::<Vec<Point>>();
-      let point = Point::new(x as u16, y as u16, z);
-      if point_lst
-        .iter()
-        .any(|p1| data.iter().any(|p2| p1.x == p2.x && p1.y == p2.y))
-      {
-        v.push(point);
-      }
-    }
-  }
-  v
-}
-
-/// 収縮
-/// 周辺8近傍が全て塗られていないといけない
-pub fn erosion(rows: i16, columns: i16, z: u16, data: &[Point]) -> Vec<Point> {
-  let mut v = Vec::new();
-  for x in 0..rows {
-    for y in 0..columns {
-      let point_lst = [
-        (x - 1, y, z),
-        (x + 1, y, z),
-        (x, y - 1, z),
-        (x, y + 1, z),
-        (x - 1, y - 1, z),
-        (x + 1, y + 1, z),
-        (x - 1, y + 1, z),
-        (x + 1, y - 1, z),
-      ]
-      .iter()
-      .filter(|(x, y, _)| *x >= 0 && *y >= 0)
-      .map(|(x, y, z)| Point::new(*x as u16, *y as u16, *z))
-      .collect::<Vec<Point>>();
-      let point = Point::new(x as u16, y as u16, z);
-      if point_lst
-        .iter()
-        .all(|p1| data.iter().any(|p2| p1.x == p2.x && p1.y == p2.y))
-      {
-        v.push(point);
-      }
-    }
-  }
-  v
-}
-
-/// 同じ回数分だけ収縮して膨張する
-#[allow(dead_code)]
-pub fn opening(rows: i16, columns: i16, z: u16, data: &[Point], n: usize) -> Vec<Point> {
-  let mut v = data.to_vec();
-  for _ in 0..n {
-    v = erosion(rows, columns, z, &v);
-  }
-  for _ in 0..n {
-    v = diation(rows, columns, z, &v);
-  }
-  v
-}
-
-/// 同じ回数分だけ膨張して収縮する
-#[allow(dead_code)]
-pub fn closing(rows: i16, columns: i16, z: u16, data: &[Point], n: usize) -> Vec<Point> {
-  let mut v = data.to_vec();
-  for _ in 0..n {
-    v = diation(rows, columns, z, &v);
-  }
-  for _ in 0..n {
-    v = erosion(rows, columns, z, &v);
-  }
-  v
-}
@@ -0,0 +1,265 @@
+use crate::filter::{NO_GROUP, OUTSIDE};
+use crate::volume::Volume;
+use clap::ValueEnum;
+use ndarray::{Array2, Array3, ArrayView2, Axis, Zip};
+
+/// 間隔が中央値のこの倍数より大きければスライスが抜けているとみなす
+const GAP_RATIO: f64 = 1.5;
+
+/// 抜けているスライスを補う方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GapFill {
+  /// 前後のスライスのCT値を線形補間する
+  Intensity,
+  /// 分類した後に、前後のスライスのラベルの形を補間する
+  Shape,
+}
+
+/// スライスの抜け
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Gap {
+  /// 抜けの直前のスライスの番号
+  pub after: usize,
+  /// 抜けているスライスの枚数
+  pub missing: usize,
+}
+
+/// 並べ替えたスライスの位置から抜けを探す
+///
+/// スライスの間隔の中央値の`GAP_RATIO`倍より離れている箇所を抜けとする
+pub fn find_gaps(keys: &[f64]) -> Vec<Gap> {
+  let diffs = keys.windows(2).map(|w| w[1] - w[0]).collect::<Vec<_>>();
+  let mut sorted = diffs.clone();
+  sorted.sort_by(|d1, d2| d1.total_cmp(d2));
+  let median = match sorted.get(sorted.len() / 2) {
+    Some(median) if *median > 0.0 => *median,
+    _ => return Vec::new(),
+  };
+  diffs
+    .iter()
+    .enumerate()
+    .filter(|(_, d)| **d > median * GAP_RATIO)
+    .map(|(i, d)| Gap {
+      after: i,
+      missing: ((d / median).round() as usize).saturating_sub(1).max(1),
+    })
+    .collect()
+}
+
+/// 抜けを補った後の各スライスが元のどのスライスに当たるか
+/// 元のスライスは`Ok(番号)`、補うスライスは`Err((直前のスライスの番号, 補間の割合))`
+fn filled_index(height: usize, gaps: &[Gap]) -> Vec<Result<usize, (usize, f64)>> {
+  let mut v = Vec::new();
+  for z in 0..height {
+    v.push(Ok(z));
+    if let Some(gap) = gaps.iter().find(|g| g.after == z && z + 1 < height) {
+      for j in 1..=gap.missing {
+        v.push(Err((z, j as f64 / (gap.missing + 1) as f64)));
+      }
+    }
+  }
+  v
+}
+
+/// 抜けているスライスを前後のスライスのCT値の線形補間で補う
+pub fn fill_intensity(volume: &Volume, gaps: &[Gap]) -> Volume {
+  let index = filled_index(volume.height(), gaps);
+  let mut data = Array3::zeros((index.len(), volume.rows(), volume.columns()));
+  for (mut slice, i) in data.axis_iter_mut(Axis(0)).zip(index) {
+    match i {
+      Ok(z) => slice.assign(&volume.data.index_axis(Axis(0), z)),
+      Err((z, t)) => {
+        let a = volume.data.index_axis(Axis(0), z);
+        let b = volume.data.index_axis(Axis(0), z + 1);
+        Zip::from(&mut slice)
+          .and(&a)
+          .and(&b)
+          .for_each(|v, a, b| *v = (*a as f64 * (1.0 - t) + *b as f64 * t).round() as i16);
+      }
+    }
+  }
+  Volume {
+    data,
+    geometry: volume.geometry,
+  }
+}
+
+/// 1次元の距離変換（距離の2乗）
+///
+/// Felzenszwalb, P. F. and Huttenlocher, D. P. "Distance Transforms of Sampled Functions" (2012)
+fn distance_transform_1d(f: &[f64]) -> Vec<f64> {
+  let n = f.len();
+  let first = match f.iter().position(|x| x.is_finite()) {
+    Some(i) => i,
+    None => return vec![f64::INFINITY; n],
+  };
+  let mut v = vec![first];
+  let mut z = vec![f64::NEG_INFINITY, f64::INFINITY];
+  for q in first + 1..n {
+    if !f[q].is_finite() {
+      continue;
+    }
+    loop {
+      let p = *v.last().unwrap();
+      let s = ((f[q] + (q * q) as f64) - (f[p] + (p * p) as f64)) / (2.0 * (q - p) as f64);
+      if s <= z[v.len() - 1] && v.len() > 1 {
+        v.pop();
+        z.pop();
+      } else {
+        v.push(q);
+        *z.last_mut().unwrap() = s;
+        z.push(f64::INFINITY);
+        break;
+      }
+    }
+  }
+  let mut k = 0;
+  (0..n)
+    .map(|q| {
+      while z[k + 1] < q as f64 {
+        k += 1;
+      }
+      let p = v[k];
+      (q as f64 - p as f64).powi(2) + f[p]
+    })
+    .collect()
+}
+
+/// `mask`が`true`の画素までのユークリッド距離（画素単位）
+fn distance_transform(mask: &Array2<bool>) -> Array2<f64> {
+  let mut d = mask.mapv(|m| if m { 0.0 } else { f64::INFINITY });
+  for axis in [Axis(1), Axis(0)] {
+    for mut lane in d.lanes_mut(axis) {
+      let line = distance_transform_1d(&lane.to_vec());
+      for (item, v) in lane.iter_mut().zip(line) {
+        *item = v;
+      }
+    }
+  }
+  d.mapv(f64::sqrt)
+}
+
+/// グループ`label`の符号付き距離（内側が負）
+/// そのグループの画素が無い場合は`None`を返す
+fn signed_distance(slice: ArrayView2<'_, u8>, label: u8) -> Option<Array2<f64>> {
+  let inside = slice.mapv(|l| l == label);
+  if !inside.iter().any(|v| *v) {
+    return None;
+  }
+  let outside = inside.mapv(|v| !v);
+  let to_inside = distance_transform(&inside);
+  let to_outside = distance_transform(&outside);
+  Some(
+    Zip::from(&to_inside)
+      .and(&to_outside)
+      .map_collect(|i, o| if *i == 0.0 { -*o } else { *i }),
+  )
+}
+
+/// 抜けているスライスを前後のスライスのラベルの形から補う
+///
+/// グループごとに符号付き距離を線形補間し、最も内側にあるグループを選ぶ
+/// 体の外側（`OUTSIDE`）も1つのグループとして補間する
+pub fn fill_labels(labels: &Array3<u8>, gaps: &[Gap], group_size: usize) -> Array3<u8> {
+  let (height, rows, columns) = labels.dim();
+  let index = filled_index(height, gaps);
+  // 片方のスライスにしか無いグループは、もう片方では十分遠くにあるとみなす
+  let far = (rows + columns) as f64;
+  let mut v = Array3::from_elem((index.len(), rows, columns), NO_GROUP);
+  for (mut slice, i) in v.axis_iter_mut(Axis(0)).zip(index) {
+    match i {
+      Ok(z) => slice.assign(&labels.index_axis(Axis(0), z)),
+      Err((z, t)) => {
+        let a = labels.index_axis(Axis(0), z);
+        let b = labels.index_axis(Axis(0), z + 1);
+        let mut best = Array2::from_elem((rows, columns), f64::INFINITY);
+        for label in (0..group_size as u8).chain([OUTSIDE]) {
+          let d = match (signed_distance(a, label), signed_distance(b, label)) {
+            (Some(da), Some(db)) => da * (1.0 - t) + db * t,
+            (Some(da), None) => da * (1.0 - t) + far * t,
+            (None, Some(db)) => db * t + far * (1.0 - t),
+            (None, None) => continue,
+          };
+          Zip::from(&mut slice)
+            .and(&mut best)
+            .and(&d)
+            .for_each(|l, best, d| {
+              if *d < *best {
+                *best = *d;
+                *l = label;
+              }
+            });
+        }
+      }
+    }
+  }
+  v
+}
+
+#[cfg(test)]
+mod gap_test {
+  use crate::gap::*;
+  use crate::geometry::Geometry;
+
+  #[test]
+  fn check_find_gaps() {
+    let keys = [0.0, 2.5, 5.0, 10.0, 12.5, 20.0];
+    assert_eq!(
+      find_gaps(&keys),
+      vec![
+        Gap {
+          after: 2,
+          missing: 1
+        },
+        Gap {
+          after: 4,
+          missing: 2
+        }
+      ]
+    );
+    assert!(find_gaps(&[0.0, 2.5, 5.0]).is_empty());
+  }
+
+  #[test]
+  fn check_fill_intensity() {
+    let data = Array3::from_shape_vec((3, 1, 1), vec![0, 30, 60]).unwrap();
+    let volume = Volume {
+      data,
+      geometry: Geometry::default(),
+    };
+    let gaps = [Gap {
+      after: 1,
+      missing: 2,
+    }];
+    let filled = fill_intensity(&volume, &gaps);
+    assert_eq!(filled.data.into_raw_vec(), vec![0, 30, 40, 50, 60]);
+  }
+
+  #[test]
+  fn check_distance_transform() {
+    let mut mask = Array2::from_elem((1, 5), false);
+    mask[[0, 1]] = true;
+    let d = distance_transform(&mask);
+    assert_eq!(d.into_raw_vec(), vec![1.0, 0.0, 1.0, 2.0, 3.0]);
+  }
+
+  #[test]
+  fn check_fill_labels() {
+    // 円が大きくなっていく
+    let mut labels = Array3::from_elem((2, 11, 11), 0u8);
+    for ((z, y, x), l) in labels.indexed_iter_mut() {
+      let r = if z == 0 { 2.0 } else { 4.0 };
+      if ((x as f64 - 5.0).powi(2) + (y as f64 - 5.0).powi(2)).sqrt() <= r {
+        *l = 1;
+      }
+    }
+    let gaps = [Gap {
+      after: 0,
+      missing: 1,
+    }];
+    let filled = fill_labels(&labels, &gaps, 2);
+    assert_eq!(filled.dim(), (3, 11, 11));
+    let radius = (0..11).filter(|x| filled[[1, 5, *x]] == 1).count();
+    assert_eq!(radius, 7);
+  }
+}
@@ -0,0 +1,155 @@
+use crate::resample::{index_matrix, inverse};
+use clap::ValueEnum;
+
+/// OBJファイルに書き出す頂点の座標系
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CoordinateSystem {
+  /// 画素の添字そのまま
+  Voxel,
+  /// 画素の間隔を考慮したmm単位の座標
+  Mm,
+  /// 患者座標系（DICOMと同じLPS）
+  Lps,
+  /// 患者座標系（RAS）
+  Ras,
+}
+
+/// 画素の添字と物理的な座標との対応
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Geometry {
+  /// x, y, z方向の画素の間隔（mm）
+  pub spacing: [f64; 3],
+  /// (0, 0, 0)の画素の患者座標（mm）
+  pub origin: [f64; 3],
+  /// x, y, z方向の添字が増える向きの単位ベクトル（LPS）
+  pub direction: [[f64; 3]; 3],
+  /// 切り出す前の画像の(0, 0, 0)の画素から見た、(0, 0, 0)の画素のx, y, z方向の位置（mm）
+  pub offset: [f64; 3],
+  /// OBJファイルの`voxel`座標系で1画素とするx, y, z方向の長さ（mm）
+  /// `None`の場合は`spacing`と同じ
+  pub voxel_spacing: Option<[f64; 3]>,
+}
+
+impl Default for Geometry {
+  fn default() -> Self {
+    Geometry {
+      spacing: [1.0, 1.0, 1.0],
+      origin: [0.0, 0.0, 0.0],
+      direction: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+      offset: [0.0, 0.0, 0.0],
+      voxel_spacing: None,
+    }
+  }
+}
+
+impl Geometry {
+  /// 画素の添字で表された座標を指定した座標系に変換する
+  pub fn transform(&self, system: CoordinateSystem, p: (f32, f32, f32)) -> (f32, f32, f32) {
+    let index = [p.0 as f64, p.1 as f64, p.2 as f64];
+    match system {
+      CoordinateSystem::Voxel => {
+        let unit = self.voxel_spacing.unwrap_or(self.spacing);
+        let v =
+          [0, 1, 2].map(|axis| (index[axis] * self.spacing[axis] + self.offset[axis]) / unit[axis]);
+        (v[0] as f32, v[1] as f32, v[2] as f32)
+      }
+      CoordinateSystem::Mm => (
+        (index[0] * self.spacing[0] + self.offset[0]) as f32,
+        (index[1] * self.spacing[1] + self.offset[1]) as f32,
+        (index[2] * self.spacing[2] + self.offset[2]) as f32,
+      ),
+      CoordinateSystem::Lps | CoordinateSystem::Ras => {
+        let mut v = self.origin;
+        for (axis, i) in index.iter().enumerate() {
+          for (k, item) in v.iter_mut().enumerate() {
+            *item += i * self.spacing[axis] * self.direction[axis][k];
+          }
+        }
+        if system == CoordinateSystem::Ras {
+          v[0] = -v[0];
+          v[1] = -v[1];
+        }
+        (v[0] as f32, v[1] as f32, v[2] as f32)
+      }
+    }
+  }
+
+  /// 指定した座標系で表された座標を実数の画素の添字に変換する
+  /// 向きが退化していて変換できない場合は`None`を返す
+  pub fn index_of(&self, system: CoordinateSystem, p: [f64; 3]) -> Option<[f64; 3]> {
+    match system {
+      CoordinateSystem::Voxel => {
+        let unit = self.voxel_spacing.unwrap_or(self.spacing);
+        Some([0, 1, 2].map(|axis| (p[axis] * unit[axis] - self.offset[axis]) / self.spacing[axis]))
+      }
+      CoordinateSystem::Mm => {
+        Some([0, 1, 2].map(|axis| (p[axis] - self.offset[axis]) / self.spacing[axis]))
+      }
+      CoordinateSystem::Lps | CoordinateSystem::Ras => {
+        let mut v = p;
+        if system == CoordinateSystem::Ras {
+          v[0] = -v[0];
+          v[1] = -v[1];
+        }
+        let d = [0, 1, 2].map(|k| v[k] - self.origin[k]);
+        let m = inverse(index_matrix(self))?;
+        Some([0, 1, 2].map(|i| (0..3).map(|k| m[i][k] * d[k]).sum::<f64>()))
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod geometry_test {
+  use crate::geometry::*;
+
+  #[test]
+  fn check_transform() {
+    let geometry = Geometry {
+      spacing: [0.5, 0.5, 2.0],
+      origin: [-100.0, -50.0, 10.0],
+      ..Default::default()
+    };
+    let p = (2.0, 4.0, 3.0);
+    assert_eq!(geometry.transform(CoordinateSystem::Voxel, p), p);
+    assert_eq!(geometry.transform(CoordinateSystem::Mm, p), (1.0, 2.0, 6.0));
+    assert_eq!(
+      geometry.transform(CoordinateSystem::Lps, p),
+      (-99.0, -48.0, 16.0)
+    );
+    assert_eq!(
+      geometry.transform(CoordinateSystem::Ras, p),
+      (99.0, 48.0, 16.0)
+    );
+  }
+
+  #[test]
+  fn check_index_of() {
+    let geometry = Geometry {
+      spacing: [0.5, 0.5, 2.0],
+      origin: [-100.0, -50.0, 10.0],
+      offset: [1.0, 0.0, 4.0],
+      ..Default::default()
+    };
+    assert_eq!(
+      geometry.transform(CoordinateSystem::Voxel, (2.0, 4.0, 3.0)),
+      (4.0, 4.0, 5.0)
+    );
+    assert_eq!(
+      geometry.transform(CoordinateSystem::Mm, (2.0, 4.0, 3.0)),
+      (2.0, 2.0, 10.0)
+    );
+    for system in [
+      CoordinateSystem::Voxel,
+      CoordinateSystem::Mm,
+      CoordinateSystem::Lps,
+      CoordinateSystem::Ras,
+    ] {
+      let (x, y, z) = geometry.transform(system, (2.0, 4.0, 3.0));
+      let index = geometry
+        .index_of(system, [x as f64, y as f64, z as f64])
+        .unwrap();
+      assert_eq!(index, [2.0, 4.0, 3.0]);
+    }
+  }
+}
@@ -0,0 +1,208 @@
+use std::f64::consts::PI;
+
+/// 分散がこれより小さくならないようにする（HUの2乗）
+/// 1つのCT値だけに集中した成分で尤度が発散するのを防ぐ
+const MIN_VARIANCE: f64 = 1.0;
+
+/// 対数尤度の変化がこれより小さくなったら収束したとみなす
+const TOLERANCE: f64 = 1e-6;
+
+/// EMアルゴリズムの繰り返しの上限
+const MAX_ITERATIONS: usize = 500;
+
+/// 混合ガウス分布の1つの成分
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Component {
+  /// 平均（HU）
+  pub mean: f64,
+  /// 分散（HUの2乗）
+  pub variance: f64,
+  /// 混合比
+  pub weight: f64,
+}
+
+impl Component {
+  /// 重みを掛けた確率密度
+  fn density(&self, x: f64) -> f64 {
+    let d = x - self.mean;
+    self.weight * (-d * d / (2.0 * self.variance)).exp() / (2.0 * PI * self.variance).sqrt()
+  }
+}
+
+/// CT値`x`が各成分に属する事後確率
+pub fn posterior(components: &[Component], x: f64) -> Vec<f64> {
+  let densities = components.iter().map(|c| c.density(x)).collect::<Vec<_>>();
+  let total = densities.iter().sum::<f64>();
+  if total > 0.0 {
+    densities.iter().map(|d| d / total).collect()
+  } else {
+    // どの成分からも遠すぎる場合は平均が最も近い成分とする
+    let nearest = (0..components.len())
+      .min_by(|i, j| {
+        let di = (x - components[*i].mean).abs();
+        let dj = (x - components[*j].mean).abs();
+        di.total_cmp(&dj)
+      })
+      .unwrap_or(0);
+    (0..components.len())
+      .map(|i| if i == nearest { 1.0 } else { 0.0 })
+      .collect()
+  }
+}
+
+/// CT値`x`の事後確率が最も大きい成分の番号
+/// その事後確率が`threshold`より小さい場合は`None`とする
+pub fn classify(components: &[Component], x: f64, threshold: f64) -> Option<usize> {
+  posterior(components, x)
+    .into_iter()
+    .enumerate()
+    .max_by(|(_, p1), (_, p2)| p1.total_cmp(p2))
+    .filter(|(_, p)| *p >= threshold)
+    .map(|(i, _)| i)
+}
+
+/// CT値のヒストグラムに混合ガウス分布をEMアルゴリズムで当てはめる
+///
+/// `histogram`はCT値と画素の数の組、`init_means`は各成分の平均の初期値
+/// 初期の分散は全体の分散を成分の数の2乗で割ったもの、混合比は等しくする
+pub fn fit(histogram: &[(i16, usize)], init_means: &[f64]) -> Vec<Component> {
+  let k = init_means.len();
+  let n = histogram
+    .iter()
+    .map(|(_, count)| *count as f64)
+    .sum::<f64>();
+  if k == 0 || n == 0.0 {
+    return Vec::new();
+  }
+  let mean = histogram
+    .iter()
+    .map(|(v, count)| *v as f64 * *count as f64)
+    .sum::<f64>()
+    / n;
+  let variance = histogram
+    .iter()
+    .map(|(v, count)| (*v as f64 - mean).powi(2) * *count as f64)
+    .sum::<f64>()
+    / n;
+  let mut components = init_means
+    .iter()
+    .map(|mean| Component {
+      mean: *mean,
+      variance: (variance / (k * k) as f64).max(MIN_VARIANCE),
+      weight: 1.0 / k as f64,
+    })
+    .collect::<Vec<_>>();
+
+  let mut log_likelihood = f64::NEG_INFINITY;
+  for _ in 0..MAX_ITERATIONS {
+    // Eステップ：CT値ごとの事後確率を画素の数で重み付けして集計する
+    let mut sum = vec![(0.0, 0.0, 0.0); k];
+    let mut new_log_likelihood = 0.0;
+    for (v, count) in histogram.iter() {
+      let x = *v as f64;
+      let count = *count as f64;
+      let total = components.iter().map(|c| c.density(x)).sum::<f64>();
+      new_log_likelihood += count * total.max(f64::MIN_POSITIVE).ln();
+      for (s, p) in sum.iter_mut().zip(posterior(&components, x)) {
+        s.0 += count * p;
+        s.1 += count * p * x;
+        s.2 += count * p * x * x;
+      }
+    }
+    // Mステップ
+    for (c, (r, rx, rxx)) in components.iter_mut().zip(sum) {
+      if r <= 0.0 {
+        continue;
+      }
+      c.mean = rx / r;
+      c.variance = (rxx / r - c.mean * c.mean).max(MIN_VARIANCE);
+      c.weight = r / n;
+    }
+    if (new_log_likelihood - log_likelihood).abs() <= TOLERANCE * new_log_likelihood.abs() {
+      break;
+    }
+    log_likelihood = new_log_likelihood;
+  }
+  components
+}
+
+#[cfg(test)]
+mod gmm_test {
+  use crate::filter::{closing_block, opening_block, NO_GROUP};
+  use crate::gmm::*;
+  use crate::k_means::label_by_value;
+  use ndarray::Array3;
+
+  #[test]
+  fn check_fit() {
+    // 平均-800と40で、分散が異なる2つの山
+    let mut histogram = Vec::new();
+    for d in -30i16..=30 {
+      let count = (1000.0 * (-(d as f64).powi(2) / (2.0 * 100.0)).exp()) as usize;
+      histogram.push((-800 + d, count));
+    }
+    for d in -30i16..=30 {
+      let count = (300.0 * (-(d as f64).powi(2) / (2.0 * 25.0)).exp()) as usize;
+      histogram.push((40 + d, count));
+    }
+    histogram.retain(|(_, count)| *count > 0);
+    let components = fit(&histogram, &[-500.0, 0.0]);
+    assert!((components[0].mean + 800.0).abs() < 0.5);
+    assert!((components[1].mean - 40.0).abs() < 0.5);
+    assert!((components[0].variance - 100.0).abs() < 5.0);
+    assert!((components[1].variance - 25.0).abs() < 5.0);
+    assert!(components[0].weight > components[1].weight);
+    assert!((components[0].weight + components[1].weight - 1.0).abs() < 1e-9);
+  }
+
+  #[test]
+  fn check_posterior() {
+    let components = [
+      Component {
+        mean: 0.0,
+        variance: 1.0,
+        weight: 0.5,
+      },
+      Component {
+        mean: 10.0,
+        variance: 1.0,
+        weight: 0.5,
+      },
+    ];
+    let p = posterior(&components, 5.0);
+    assert!((p[0] - 0.5).abs() < 1e-9);
+    assert_eq!(posterior(&components, 1e6), vec![0.0, 1.0]);
+  }
+
+  #[tokio::test]
+  async fn check_classify_filtered() {
+    // 2つの成分の中間の面は事後確率がしきい値に届かず、ノイズ除去の後もどのグループにも属さない
+    let components = [
+      Component {
+        mean: 0.0,
+        variance: 100.0,
+        weight: 0.5,
+      },
+      Component {
+        mean: 100.0,
+        variance: 100.0,
+        weight: 0.5,
+      },
+    ];
+    let data = Array3::from_shape_fn((5, 5, 5), |(_, _, x)| (x as i16 - 1) * 50);
+    let labels = label_by_value(&data, None, |value| {
+      classify(&components, value as f64, 0.9)
+        .map(|i| i as u8)
+        .unwrap_or(NO_GROUP)
+    });
+    assert_eq!(labels.iter().filter(|l| **l == NO_GROUP).count(), 25);
+    for filtered in [
+      opening_block(&labels, 1).await,
+      closing_block(&labels, 1).await,
+    ] {
+      for ((_, _, x), label) in filtered.indexed_iter() {
+        assert_eq!(*label == NO_GROUP, x == 2);
+      }
+    }
+  }
+}
@@ -1,57 +1,599 @@
-use tokio_stream::StreamExt;
+use crate::filter::OUTSIDE;
+use crate::Point;
+use clap::ValueEnum;
+use ndarray::Array3;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
-pub async fn solve<T, C, F, G, E>(
+/// 初期値やグループの数を選ぶときに使う画素の数
+pub const SAMPLE_SIZE: usize = 20000;
+
+/// シルエット係数を計算するときに使う画素の数
+const SILHOUETTE_SIZE: usize = 2000;
+
+/// 同じ結果になるように乱数の種を固定する
+const SEED: u64 = 0;
+
+/// グループの数を自動で選ぶ方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AutoK {
+  /// グループ内の分散の減り方が緩やかになる点（エルボー法）
+  Elbow,
+  /// シルエット係数が最も大きくなる数
+  Silhouette,
+}
+
+/// グループに属する画素の集計
+/// 重心の計算に使う
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Summary {
+  /// 画素の数
+  pub count: usize,
+  /// CT値の和
+  pub sum: i64,
+  /// x, y, z座標の和
+  pub sum_point: [u64; 3],
+}
+
+/// 重心の更新を打ち切る条件
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Options {
+  /// 全ての重心の移動量がこの値以下になったら収束したとみなす
+  pub tolerance: f64,
+  /// 重心を更新する回数の上限
+  pub max_iterations: usize,
+}
+
+/// 分類の結果
+#[derive(Debug, Clone, PartialEq)]
+pub struct Solution<C> {
+  /// `[[z, y, x]]`で添字を与えるグループの番号
+  pub labels: Array3<u8>,
+  /// ラベルを付けるのに使った重心
+  pub centers: Vec<C>,
+  /// 上限に達する前に収束したかどうか
+  pub converged: bool,
+  /// 全ての画素をグループに分けた回数
+  pub iterations: usize,
+}
+
+/// 全体から等間隔に最大`size`個の画素のCT値を取り出す
+/// `mask`を与えた場合は`true`の画素だけから選ぶ
+pub fn sample(data: &Array3<i16>, mask: Option<&Array3<bool>>, size: usize) -> Vec<i16> {
+  let values = match mask {
+    Some(mask) => data
+      .iter()
+      .zip(mask.iter())
+      .filter(|(_, inside)| **inside)
+      .map(|(v, _)| *v)
+      .collect::<Vec<_>>(),
+    None => data.iter().copied().collect(),
+  };
+  let step = values.len().div_ceil(size.max(1)).max(1);
+  values.into_iter().step_by(step).collect()
+}
+
+/// k-means++で`k`個の初期値を選ぶ
+///
+/// 既に選んだ値から遠いものほど選ばれやすくし、小さい順に並べて返す
+pub fn kmeans_pp(sample: &[i16], k: usize) -> Vec<i16> {
+  let mut rng = StdRng::seed_from_u64(SEED);
+  let mut centers = Vec::new();
+  if sample.is_empty() {
+    return centers;
+  }
+  centers.push(sample[rng.gen_range(0..sample.len())]);
+  let mut distance = sample
+    .iter()
+    .map(|v| (*v as f64 - centers[0] as f64).powi(2))
+    .collect::<Vec<_>>();
+  while centers.len() < k {
+    let total = distance.iter().sum::<f64>();
+    if total <= 0.0 {
+      // 異なる値が足りない
+      break;
+    }
+    let mut r = rng.gen_range(0.0..total);
+    let i = distance
+      .iter()
+      .position(|d| {
+        r -= d;
+        r < 0.0
+      })
+      .unwrap_or(sample.len() - 1);
+    let center = sample[i];
+    centers.push(center);
+    for (d, v) in distance.iter_mut().zip(sample) {
+      *d = d.min((*v as f64 - center as f64).powi(2));
+    }
+  }
+  centers.sort();
+  centers
+}
+
+/// 取り出した画素を1次元のk-meansで分け、重心とグループ内の二乗誤差の和を返す
+fn lloyd(sample: &[i16], init: &[i16]) -> (Vec<f64>, Vec<usize>, f64) {
+  let mut centers = init.iter().map(|c| *c as f64).collect::<Vec<_>>();
+  let nearest = |centers: &[f64], v: i16| {
+    (0..centers.len())
+      .min_by(|i, j| {
+        let di = (v as f64 - centers[*i]).abs();
+        let dj = (v as f64 - centers[*j]).abs();
+        di.total_cmp(&dj)
+      })
+      .unwrap()
+  };
+  let mut assign = vec![0; sample.len()];
+  for _ in 0..100 {
+    for (a, v) in assign.iter_mut().zip(sample) {
+      *a = nearest(&centers, *v);
+    }
+    let mut sum = vec![(0.0, 0); centers.len()];
+    for (a, v) in assign.iter().zip(sample) {
+      sum[*a].0 += *v as f64;
+      sum[*a].1 += 1;
+    }
+    let new_centers = sum
+      .iter()
+      .zip(centers.iter())
+      .map(|((s, n), c)| if *n > 0 { s / *n as f64 } else { *c })
+      .collect::<Vec<_>>();
+    if new_centers == centers {
+      break;
+    }
+    centers = new_centers;
+  }
+  let sse = assign
+    .iter()
+    .zip(sample)
+    .map(|(a, v)| (*v as f64 - centers[*a]).powi(2))
+    .sum::<f64>();
+  (centers, assign, sse)
+}
+
+/// 1次元のデータのシルエット係数の平均
+fn silhouette(sample: &[i16], assign: &[usize], k: usize) -> f64 {
+  let n = sample.len();
+  let mut total = 0.0;
+  for i in 0..n {
+    let mut sum = vec![(0.0, 0); k];
+    for j in 0..n {
+      if i != j {
+        sum[assign[j]].0 += (sample[i] as f64 - sample[j] as f64).abs();
+        sum[assign[j]].1 += 1;
+      }
+    }
+    let (own_sum, own_count) = sum[assign[i]];
+    if own_count == 0 {
+      continue;
+    }
+    let a = own_sum / own_count as f64;
+    let b = sum
+      .iter()
+      .enumerate()
+      .filter(|(c, (_, count))| *c != assign[i] && *count > 0)
+      .map(|(_, (s, count))| s / *count as f64)
+      .fold(f64::INFINITY, f64::min);
+    if b.is_finite() && a.max(b) > 0.0 {
+      total += (b - a) / a.max(b);
+    }
+  }
+  total / n.max(1) as f64
+}
+
+/// 2から`max_k`までの中からグループの数を選ぶ
+pub fn choose_k(sample: &[i16], max_k: usize, method: AutoK) -> usize {
+  let max_k = max_k.max(2);
+  match method {
+    AutoK::Elbow => {
+      // 1個から`max_k`個までの二乗誤差の曲線で、両端を結ぶ直線から最も離れた点を選ぶ
+      let sse = (1..=max_k)
+        .map(|k| lloyd(sample, &kmeans_pp(sample, k)).2)
+        .collect::<Vec<_>>();
+      let (first, last) = (sse[0], sse[max_k - 1]);
+      let scale = (first - last).max(f64::EPSILON);
+      (2..max_k)
+        .map(|k| {
+          let t = (k - 1) as f64 / (max_k - 1) as f64;
+          let line = first + (last - first) * t;
+          (k, (line - sse[k - 1]) / scale)
+        })
+        .max_by(|(_, d1), (_, d2)| d1.total_cmp(d2))
+        .map(|(k, _)| k)
+        .unwrap_or(2)
+    }
+    AutoK::Silhouette => {
+      let step = sample.len().div_ceil(SILHOUETTE_SIZE).max(1);
+      let small = sample.iter().copied().step_by(step).collect::<Vec<_>>();
+      (2..=max_k)
+        .map(|k| {
+          let (_, assign, _) = lloyd(&small, &kmeans_pp(&small, k));
+          (k, silhouette(&small, &assign, k))
+        })
+        .max_by(|(_, s1), (_, s2)| s1.total_cmp(s2))
+        .map(|(k, _)| k)
+        .unwrap_or(2)
+    }
+  }
+}
+
+/// CT値だけで各画素を一番近い重心のグループに分ける
+///
+/// 同じCT値の画素は必ず同じグループになるので、CT値ごとの画素の数（ヒストグラム）で
+/// 重心を求めてから、最後に1度だけ全ての画素にラベルを付ける
+/// `calc_center`に渡す`Summary`の`sum_point`は計算しない
+/// 引数と戻り値、`mask`の扱いは`solve`と同じ
+pub async fn solve_histogram<C, F, G, M>(
   calc_distance: F,
   calc_center: G,
-  calc_eq: E,
+  calc_movement: M,
   init_center: Vec<C>,
-  lst: &[T],
-) -> Vec<Vec<T>>
+  data: &Array3<i16>,
+  mask: Option<&Array3<bool>>,
+  options: Options,
+) -> Solution<C>
 where
-  T: Sized + Clone,
   C: Sized + Clone,
-  F: Fn(&C, &T) -> usize,
-  G: Fn(&[T]) -> Option<C>,
-  E: Fn(&[T], &[T]) -> bool,
+  F: Fn(&C, i16) -> usize,
+  G: Fn(&Summary) -> Option<C>,
+  M: Fn(&C, &C) -> f64,
 {
+  let values = histogram(data, mask);
+  let nearest = |center_lst: &[C], value: i16| {
+    center_lst
+      .iter()
+      .enumerate()
+      .map(|(i, center)| (i, calc_distance(center, value)))
+      .min_by_key(|(_, d)| *d)
+      .map(|(i, _)| i)
+      .unwrap()
+  };
   let n = init_center.len();
-  let mut l1: Vec<Vec<T>> = Vec::new();
-  let mut l2: Vec<Vec<T>> = vec![Vec::new(); n];
-  let mut center_lst: Vec<C> = init_center;
-  loop {
-    let mut data_stream = tokio_stream::iter(lst);
+  let mut center_lst = init_center;
+  let mut converged = false;
+  let mut iterations = 0;
+  while iterations < options.max_iterations {
+    iterations += 1;
+    let mut summary_lst = vec![Summary::default(); n];
+    for (value, count) in values.iter() {
+      let summary = &mut summary_lst[nearest(&center_lst, *value)];
+      summary.count += count;
+      summary.sum += *value as i64 * *count as i64;
+    }
+    let new_center_lst = summary_lst
+      .iter()
+      .zip(center_lst.iter())
+      .map(|(summary, center)| calc_center(summary).unwrap_or_else(|| center.clone()))
+      .collect::<Vec<_>>();
+    if is_converged(&calc_movement, &center_lst, &new_center_lst, options) {
+      converged = true;
+      break;
+    }
+    if iterations == options.max_iterations {
+      // ラベルと重心が食い違わないように、最後に使った重心を返す
+      break;
+    }
+    tracing::info!("loop");
+    center_lst = new_center_lst;
+  }
+
+  // CT値からグループへの対応表を作って1度だけラベルを付ける
+  let labels = label_by_value(data, mask, |value| nearest(&center_lst, value) as u8);
+  Solution {
+    labels,
+    centers: center_lst,
+    converged,
+    iterations,
+  }
+}
+
+/// 全ての重心の移動量が`options.tolerance`以下かどうか
+fn is_converged<C, M>(calc_movement: &M, old: &[C], new: &[C], options: Options) -> bool
+where
+  M: Fn(&C, &C) -> f64,
+{
+  old
+    .iter()
+    .zip(new.iter())
+    .all(|(c1, c2)| calc_movement(c1, c2) <= options.tolerance)
+}
+
+/// 配列の添字に使うためにCT値を0から始まる値にずらす
+fn value_index(v: i16) -> usize {
+  (v as i32 - i16::MIN as i32) as usize
+}
+
+/// CT値ごとの画素の数（ヒストグラム）
+///
+/// 戻り値は画素が1つ以上あるCT値と画素の数の組を、CT値の小さい順に並べたもの
+/// `mask`を与えた場合は`true`の画素だけを数える
+pub fn histogram(data: &Array3<i16>, mask: Option<&Array3<bool>>) -> Vec<(i16, usize)> {
+  let mut histogram = vec![0usize; 1 << 16];
+  match mask {
+    Some(mask) => {
+      for (v, inside) in data.iter().zip(mask.iter()) {
+        if *inside {
+          histogram[value_index(*v)] += 1;
+        }
+      }
+    }
+    None => {
+      for v in data.iter() {
+        histogram[value_index(*v)] += 1;
+      }
+    }
+  }
+  histogram
+    .iter()
+    .enumerate()
+    .filter(|(_, count)| **count > 0)
+    .map(|(i, count)| ((i as i32 + i16::MIN as i32) as i16, *count))
+    .collect()
+}
+
+/// CT値から決まる値`f`を対応表にして、全ての画素に1度で付ける
+/// `mask`を与えた場合は`false`の画素を`OUTSIDE`とする
+pub fn label_by_value<F>(data: &Array3<i16>, mask: Option<&Array3<bool>>, f: F) -> Array3<u8>
+where
+  F: Fn(i16) -> u8,
+{
+  let mut table = vec![0u8; 1 << 16];
+  let (min, max) = data.iter().fold((i16::MAX, i16::MIN), |(min, max), v| {
+    (min.min(*v), max.max(*v))
+  });
+  for value in min..=max {
+    table[value_index(value)] = f(value);
+  }
+  let mut labels = data.mapv(|v| table[value_index(v)]);
+  if let Some(mask) = mask {
+    labels.zip_mut_with(mask, |label, inside| {
+      if !*inside {
+        *label = OUTSIDE;
+      }
+    });
+  }
+  labels
+}
 
-    while let Some(data) = data_stream.next().await {
+/// 各画素を一番近い重心のグループに分ける
+///
+/// `calc_movement`で測った重心の移動量が全て`options.tolerance`以下になるか、
+/// `options.max_iterations`回に達するまで繰り返す
+/// `mask`を与えた場合は`false`の画素を分類せず、`OUTSIDE`とする
+pub async fn solve<C, F, G, M>(
+  calc_distance: F,
+  calc_center: G,
+  calc_movement: M,
+  init_center: Vec<C>,
+  data: &Array3<i16>,
+  mask: Option<&Array3<bool>>,
+  options: Options,
+) -> Solution<C>
+where
+  C: Sized + Clone,
+  F: Fn(&C, Point, i16) -> usize,
+  G: Fn(&Summary) -> Option<C>,
+  M: Fn(&C, &C) -> f64,
+{
+  let n = init_center.len();
+  let mut labels = Array3::zeros(data.dim());
+  if let Some(mask) = mask {
+    labels.zip_mut_with(mask, |label, inside| {
+      if !*inside {
+        *label = OUTSIDE;
+      }
+    });
+  }
+  let mut center_lst: Vec<C> = init_center;
+  let mut converged = false;
+  let mut iterations = 0;
+  while iterations < options.max_iterations {
+    iterations += 1;
+    let mut summary_lst = vec![Summary::default(); n];
+    for (((z, y, x), value), label) in data.indexed_iter().zip(labels.iter_mut()) {
+      if *label == OUTSIDE {
+        continue;
+      }
+      let point = Point::new(x as u16, y as u16, z as u16);
       // 一番近い重心のグループを選ぶ
       let (center_num, _) = center_lst
         .iter()
         .enumerate()
-        .map(|(i, center)| (i, calc_distance(center, data)))
+        .map(|(i, center)| (i, calc_distance(center, point, *value)))
         .min_by_key(|(_, d)| *d)
         .unwrap();
       // 更新
-      l2[center_num].push(data.clone());
+      *label = center_num as u8;
+      let summary = &mut summary_lst[center_num];
+      summary.count += 1;
+      summary.sum += *value as i64;
+      summary.sum_point[0] += x as u64;
+      summary.sum_point[1] += y as u64;
+      summary.sum_point[2] += z as u64;
     }
 
-    if l1.iter().zip(l2.iter()).all(|(v1, v2)| calc_eq(v1, v2)) {
+    let new_center_lst = summary_lst
+      .iter()
+      .zip(center_lst.iter())
+      .map(|(summary, center)| calc_center(summary).unwrap_or_else(|| center.clone()))
+      .collect::<Vec<_>>();
+    if is_converged(&calc_movement, &center_lst, &new_center_lst, options) {
       // 変動しなくなったら終了
+      converged = true;
       break;
-    } else {
-      tracing::info!("loop");
-      let mut new_center_list = Vec::new();
-      for i in 0..l2.len() {
-        let new_center_opt = calc_center(&l2[i]);
-        if let Some(new_center) = new_center_opt {
-          new_center_list.push(new_center)
-        } else {
-          new_center_list.push(center_lst[i].clone())
-        }
-      }
-      //center_lst = l2.iter().map(|l| calc_center(l)).collect();
-      center_lst = new_center_list;
-      l1 = l2;
-      l2 = vec![Vec::new(); n];
     }
+    if iterations == options.max_iterations {
+      // ラベルと重心が食い違わないように、最後に使った重心を返す
+      break;
+    }
+    tracing::info!("loop");
+    center_lst = new_center_lst;
+  }
+  Solution {
+    labels,
+    centers: center_lst,
+    converged,
+    iterations,
+  }
+}
+
+#[cfg(test)]
+mod k_means_test {
+  use crate::k_means::*;
+  use ndarray::Array3;
+
+  fn calc_distance(c: &i16, v: i16) -> usize {
+    c.abs_diff(v) as usize
+  }
+
+  fn calc_center(s: &Summary) -> Option<i16> {
+    (s.count > 0).then(|| (s.sum / s.count as i64) as i16)
+  }
+
+  fn calc_movement(c1: &i16, c2: &i16) -> f64 {
+    c1.abs_diff(*c2) as f64
+  }
+
+  const OPTIONS: Options = Options {
+    tolerance: 0.0,
+    max_iterations: 100,
+  };
+
+  #[tokio::test]
+  async fn check_solve() {
+    let data = Array3::from_shape_vec((1, 2, 3), vec![-1000, -990, 40, -980, 30, 50]).unwrap();
+    let solution = solve(
+      |c, _, v| calc_distance(c, v),
+      calc_center,
+      calc_movement,
+      vec![-500, 0],
+      &data,
+      None,
+      OPTIONS,
+    )
+    .await;
+    assert_eq!(solution.labels.into_raw_vec(), vec![0, 0, 1, 0, 1, 1]);
+    assert_eq!(solution.centers, vec![-990, 40]);
+    assert!(solution.converged);
+    assert_eq!(solution.iterations, 2);
+  }
+
+  #[tokio::test]
+  async fn check_solve_mask() {
+    let data = Array3::from_shape_vec((1, 1, 4), vec![-1000, -990, 40, 50]).unwrap();
+    let mask = Array3::from_shape_vec((1, 1, 4), vec![false, true, true, true]).unwrap();
+    let solution = solve(
+      |c, _, v| calc_distance(c, v),
+      calc_center,
+      calc_movement,
+      vec![-500, 0],
+      &data,
+      Some(&mask),
+      OPTIONS,
+    )
+    .await;
+    assert_eq!(solution.labels.into_raw_vec(), vec![OUTSIDE, 0, 1, 1]);
+  }
+
+  #[tokio::test]
+  async fn check_solve_options() {
+    let data = Array3::from_shape_vec((1, 1, 4), vec![-1000, -990, 40, 50]).unwrap();
+    // 1回目で重心が-995と45に動くので、上限が1回なら収束しない
+    let solution = solve_histogram(
+      calc_distance,
+      calc_center,
+      calc_movement,
+      vec![-500, 0],
+      &data,
+      None,
+      Options {
+        tolerance: 0.0,
+        max_iterations: 1,
+      },
+    )
+    .await;
+    assert!(!solution.converged);
+    assert_eq!(solution.iterations, 1);
+    assert_eq!(solution.centers, vec![-500, 0]);
+    // 移動量が許容範囲なら1回で止まる
+    let solution = solve_histogram(
+      calc_distance,
+      calc_center,
+      calc_movement,
+      vec![-990, 40],
+      &data,
+      None,
+      Options {
+        tolerance: 5.0,
+        max_iterations: 100,
+      },
+    )
+    .await;
+    assert!(solution.converged);
+    assert_eq!(solution.iterations, 1);
+  }
+
+  #[tokio::test]
+  async fn check_solve_histogram() {
+    let data = Array3::from_shape_fn((3, 7, 11), |(z, y, x)| {
+      ((z * 131 + y * 37 + x * 71) % 1500) as i16 - 1000
+    });
+    let mask = data.mapv(|v| v != -1000);
+    for mask in [None, Some(&mask)] {
+      let expected = solve(
+        |c, _, v| calc_distance(c, v),
+        calc_center,
+        calc_movement,
+        vec![-900, -500, 0, 300],
+        &data,
+        mask,
+        OPTIONS,
+      )
+      .await;
+      let result = solve_histogram(
+        calc_distance,
+        calc_center,
+        calc_movement,
+        vec![-900, -500, 0, 300],
+        &data,
+        mask,
+        OPTIONS,
+      )
+      .await;
+      assert_eq!(result, expected);
+    }
+  }
+
+  /// 3つの山がある値の並び
+  fn three_peaks() -> Vec<i16> {
+    [-1000, -500, 40]
+      .iter()
+      .flat_map(|c| (0..50).map(move |i| c + (i % 10) as i16))
+      .collect()
+  }
+
+  #[test]
+  fn check_kmeans_pp() {
+    let centers = kmeans_pp(&three_peaks(), 3);
+    assert_eq!(centers.len(), 3);
+    assert!((-1000..-990).contains(&centers[0]));
+    assert!((-500..-490).contains(&centers[1]));
+    assert!((40..50).contains(&centers[2]));
+    assert_eq!(kmeans_pp(&[7, 7, 7], 3), vec![7]);
+  }
+
+  #[test]
+  fn check_choose_k() {
+    let sample = three_peaks();
+    assert_eq!(choose_k(&sample, 6, AutoK::Elbow), 3);
+    assert_eq!(choose_k(&sample, 6, AutoK::Silhouette), 3);
+  }
+
+  #[test]
+  fn check_sample() {
+    let data = Array3::from_shape_vec((1, 1, 6), vec![0, 1, 2, 3, 4, 5]).unwrap();
+    assert_eq!(sample(&data, None, 3), vec![0, 2, 4]);
+    let mask = data.mapv(|v| v >= 2);
+    assert_eq!(sample(&data, Some(&mask), 10), vec![2, 3, 4, 5]);
   }
-  l2
 }
@@ -47,16 +47,40 @@
 //!
 //! ## 必須引数
 //!
-//! - `-f`, `--folder`：CTファイルのあるフォルダへのパス
+//! - `-f`, `--folder`：CTファイルのあるフォルダかDICOMDIR、NIfTI（`.nii`, `.nii.gz`）、MetaImage（`.mhd`, `.mha`）、NRRD（`.nrrd`, `.nhdr`）ファイルへのパス。ファイルの形式は拡張子と先頭のバイト列から判定します。フォルダの中は再帰的に探索し、DICOMDIRがあればそこから参照されている画像を読み込みます。DICOMの画像でないファイルは警告を出して読み飛ばします。
 //! - `-o`, `--output`：生成するファイルへのパス
 //!
 //! ## オプション引数
 //!
-//! - `-d`, `--depth-img`：肺の断面画像をその場に生成します。そのときの断面の深さを与えます
-//! - `-s`, `--start-range`：解析範囲を直方体の大きさに制限することができます。そのときの始点の座標です。
-//! - `-e`, `--end-range`：解析範囲を直方体の大きさに制限することができます。そのときの終点の座標です。
+//! - `-d`, `--depth-img`：肺の断面画像をその場に生成します。そのときの断面の深さを与えます。深さは範囲の切り出しや間引きをする前の元の画像の断面の番号です。
+//! - `-s`, `--start-range`：解析範囲を直方体の大きさに切り出すことができます。そのときの始点の座標です。切り出してもOBJファイルの座標は元の画像のものと変わりません。
+//! - `-e`, `--end-range`：解析範囲を直方体の大きさに切り出すことができます。そのときの終点の座標です。
+//! - `--range-coordinate`：`--start-range`と`--end-range`の座標系です。`voxel`（画素の添字、デフォルト）、`mm`（mm単位）、`lps`・`ras`（患者座標系）から選べます。
 //! - `-n`, `--noise-removal`：ノイズ除去をするときの回数です。数が大きくなればなるほどノイズが除去されますが、必要な部分も消える可能性があります。
-//! - `-i`, `--init-colors`：部位を分割する際の基準値を与えることができます。
+//! - `-i`, `--init-colors`：部位を分割する際の基準値を与えることができます。分類した後の重心はログに`centers: ...`として表示されるので、そのまま与え直すことができます。
+//! - `--no-rescale`：RescaleSlope・RescaleInterceptによるCT値（HU）への変換を行いません。既に較正されたデータを扱うときに使います。
+//! - `-c`, `--coordinate`：OBJファイルの頂点の座標系です。`voxel`（画素の添字）、`mm`（mm単位、デフォルト）、`lps`・`ras`（患者座標系）から選べます。
+//! - `--series`：解析するシリーズを番号かSeriesInstanceUIDで指定します。指定しなかった場合は最もスライスが薄い体軸断面のCTを選びます。
+//! - `--list-series`：フォルダに含まれるシリーズの一覧（説明・枚数・カーネル・スライス厚）を表示して終了します。
+//! - `-j`, `--jobs`：DICOMファイルを同時に読み込む数です。指定しなかった場合はCPUのスレッド数になります。読み込めなかったファイルはまとめて報告します。
+//! - `--isotropic`：解析の前に画素の間隔を全ての方向でこの値（mm）に揃えます。OBJファイルの座標は元の画像の単位のままです。
+//! - `--interpolation`：`--isotropic`で用いる補間の方法です。`nearest`（最近傍）、`trilinear`（線形、デフォルト）、`bspline`（3次Bスプライン）から選べます。
+//! - `--resample-back`：`--isotropic`で揃えた格子で分類した結果を、元の画素の間隔に戻してから画像とOBJファイルを生成します。
+//! - `--fill-gaps`：スライスの位置の抜けを補う方法です。`intensity`（前後のスライスのCT値を線形補間）か`shape`（分類した後にラベルの形を補間）から選べます。指定しなかった場合は抜けを報告するだけです。
+//! - `--body-mask`：CT値がしきい値より大きい画素のうち最も大きな塊を体とし、スライスごとに穴を埋めて体の輪郭を求めます。寝台と体の外の空気を除いてから分類します。
+//! - `--body-threshold`：`--body-mask`で体の輪郭を求めるときのCT値のしきい値です。デフォルトは-500です。
+//! - `--export-body-mask`：`--body-mask`で求めた体の輪郭を`<output>_body.obj`に書き出します。
+//! - `--downsample`：x, y, z方向にそれぞれ与えた個数の画素の平均を取り、画素の数を減らしてから解析します。1つだけ与えた場合は全ての方向で同じ倍率になります。`--init-colors`や`--noise-removal`を素早く試すときに使います。OBJファイルの座標は元の画像の単位のままです。`--isotropic`とは同時に使えません。
+//! - `--kmeans-pp`：k-means++で初期値を選びます。グループの数は`--init-colors`の個数（与えなかった場合は5）です。
+//! - `--auto-k`：グループの数を自動で選びます。`elbow`（グループ内の分散の減り方が緩やかになる点）か`silhouette`（一部の画素で計算したシルエット係数が最も大きくなる数）から選べます。初期値はk-means++で選びます。
+//! - `--max-k`：`--auto-k`で試すグループの数の最大値です。デフォルトは8です。
+//! - `--spatial-weight`：CT値に加えて位置も使って分類します。位置は画像の端から端までを1とし、x, y, z方向にそれぞれ与えた重みを掛けてCT値と同じ単位で扱います。1つだけ与えた場合は全ての方向で同じ重みになります。体の外の空気と肺の中の空気を分けたいときなどに使います。
+//! - `--method`：画素をグループに分ける方法です。`k-means`（k-means法、デフォルト）、`gmm`（EMアルゴリズムで当てはめた混合ガウス分布）、`otsu`（CT値のヒストグラムに対する多段階の大津の方法）から選べます。`gmm`では各成分の平均・分散・混合比を表示し、事後確率が最も大きいグループに分けます。`otsu`では`--init-colors`の個数（与えなかった場合は5）のグループに分けるしきい値を表示します。
+//! - `--posterior-threshold`：`--method gmm`で、最も大きい事後確率がこの値より小さい画素をどのグループにも入れません。
+//! - `--export-posteriors`：`--method gmm`で求めた各グループの事後確率を0から255の値にして`<output>_posterior_<部位の名前>.mha`（MetaImage）に書き出します。`--tissues`を与えなかった場合の名前はグループの番号です。
+//! - `--tolerance`：k-means法で、全ての重心の移動量がこの値（HU）以下になったら収束したとみなします。デフォルトは0です。
+//! - `--max-iterations`：k-means法で重心を更新する回数の上限です。デフォルトは100です。上限に達した場合は警告を出します。
+//! - `-t`, `--tissues`：部位の設定をJSONかTOMLのファイルで与えます。`tissues`の各要素に`name`（名前）と、`initial`（分類の初期値）か`range`（分類せずにその部位とするCT値の範囲`[最小, 最大]`）のどちらかを書きます。`color`（断面画像の色`[R, G, B]`）、`mesh`（OBJファイルを生成するか、デフォルトは`true`）、`noise_removal`（その部位だけのノイズ除去の回数）も与えられます。出力するファイルには番号の代わりに名前が付きます（例：`output_lung.obj`）。`--init-colors`、`--kmeans-pp`、`--auto-k`とは同時に使えません。
 //!
 //! # CT画像データの取得方法
 //!
@@ -71,40 +95,89 @@
 //! Copyright (c) 2024 Naoki Kaneko (a.k.a. "puripuri2100")
 //!
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, Result};
 use clap::Parser;
-use dicom::object::{open_file, Tag};
-use dicom_pixeldata::PixelDecoder;
-use regex::Regex;
+use clap::ValueEnum;
+use cluster::{calc_center, calc_distance, calc_intensity_distance, calc_spatial_center};
+use gap::{Gap, GapFill};
+use geometry::CoordinateSystem;
+use ndarray::{Array3, Axis};
+use resample::Interpolation;
 use serde::{Deserialize, Serialize};
-use tokio::fs::{self, File};
+use std::path::Path;
+use tissue::Tissue;
+use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
 use tokio_stream::StreamExt;
 use tracing::*;
+use volume::{InputFormat, Volume};
 
+mod body_mask;
+mod cluster;
 mod filter;
+mod gap;
+mod geometry;
+mod gmm;
 mod k_means;
 mod marching_cubes;
+mod otsu;
+mod read_dicom;
+mod read_metaimage;
+mod read_nifti;
+mod read_nrrd;
+mod resample;
+mod series;
+mod tissue;
+mod volume;
 mod write_image;
+mod write_metaimage;
+
+/// 画素をグループに分ける方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Method {
+  /// k-means法
+  KMeans,
+  /// EMアルゴリズムで当てはめた混合ガウス分布
+  Gmm,
+  /// CT値のヒストグラムに対する多段階の大津の方法
+  Otsu,
+}
 
 #[derive(Parser)]
 #[command(author, version)]
 struct Args {
-  /// CTファイルのあるフォルダへのパス
+  /// CTファイルのあるフォルダかDICOMDIR、NIfTI（.nii, .nii.gz）、
+  /// MetaImage（.mhd, .mha）、NRRD（.nrrd, .nhdr）ファイルへのパス
+  /// フォルダの中は再帰的に探索する
   #[arg(short, long)]
   folder: String,
   /// 生成するファイルのパス
   #[arg(short, long)]
   output: String,
-  /// 生成する画像の深さ
+  /// 生成する画像の深さ（元の画像の断面の番号）
   #[arg(short, long)]
   depth_img: Option<usize>,
   /// 解析を行う範囲の座標のスタート
-  #[arg(short, long, value_delimiter = ' ', num_args = 3)]
-  start_range: Option<Vec<usize>>,
+  #[arg(
+    short,
+    long,
+    value_delimiter = ' ',
+    num_args = 3,
+    allow_negative_numbers = true
+  )]
+  start_range: Option<Vec<f64>>,
   /// 解析を行う範囲の座標の終点
-  #[arg(short, long, value_delimiter = ' ', num_args = 3)]
-  end_range: Option<Vec<usize>>,
+  #[arg(
+    short,
+    long,
+    value_delimiter = ' ',
+    num_args = 3,
+    allow_negative_numbers = true
+  )]
+  end_range: Option<Vec<f64>>,
+  /// `--start-range`と`--end-range`の座標系
+  #[arg(long, value_enum, default_value_t = CoordinateSystem::Voxel)]
+  range_coordinate: CoordinateSystem,
   /// ノイズ除去の濃さを数値で与える
   #[arg(short, long, default_value = "1")]
   noise_removal: usize,
@@ -112,6 +185,90 @@ struct Args {
   /// 先頭の値はデフォルト値として内部で扱われます
   #[arg(short, long, value_delimiter = ' ', num_args = 2..)]
   init_colors: Option<Vec<i16>>,
+  /// 部位の名前・初期値か固定のCT値の範囲・色・OBJファイルを生成するか・ノイズ除去の回数を
+  /// 書いたJSONかTOMLの設定ファイル
+  #[arg(
+    short,
+    long,
+    conflicts_with_all = ["init_colors", "kmeans_pp", "auto_k"]
+  )]
+  tissues: Option<String>,
+  /// RescaleSlopeとRescaleInterceptによるCT値への変換を行わない
+  /// 既に較正されたデータを扱うときに用いる
+  #[arg(long)]
+  no_rescale: bool,
+  /// OBJファイルに書き出す頂点の座標系
+  #[arg(short, long, value_enum, default_value_t = CoordinateSystem::Mm)]
+  coordinate: CoordinateSystem,
+  /// 解析するシリーズの番号かSeriesInstanceUID
+  /// 与えなかった場合は最もスライスが薄い体軸断面のCTを選ぶ
+  #[arg(long)]
+  series: Option<String>,
+  /// フォルダに含まれるシリーズの一覧を表示して終了する
+  #[arg(long)]
+  list_series: bool,
+  /// 解析の前に画素の間隔をこの値（mm）に揃える
+  #[arg(long)]
+  isotropic: Option<f64>,
+  /// `--isotropic`で用いる補間の方法
+  #[arg(long, value_enum, default_value_t = Interpolation::Trilinear)]
+  interpolation: Interpolation,
+  /// `--isotropic`で並べ直した後、分類の結果を元の格子に戻す
+  #[arg(long, requires = "isotropic")]
+  resample_back: bool,
+  /// 画素の平均を取り、画素の数をこの倍率で減らす（x, y, zの順）
+  /// 1つだけ与えた場合は全ての方向で同じ倍率にする
+  /// 設定を素早く試したいときに用いる
+  #[arg(long, value_delimiter = ' ', num_args = 1..=3, conflicts_with = "isotropic")]
+  downsample: Option<Vec<usize>>,
+  /// 抜けているスライスを補う方法
+  /// 与えなかった場合は抜けを報告するだけで補わない
+  #[arg(long, value_enum)]
+  fill_gaps: Option<GapFill>,
+  /// k-means++で初期値を選ぶ
+  /// グループの数は`--init-colors`の個数（与えなかった場合は5）
+  #[arg(long)]
+  kmeans_pp: bool,
+  /// グループの数を自動で選ぶ方法
+  /// 初期値はk-means++で選ぶ
+  #[arg(long, value_enum)]
+  auto_k: Option<k_means::AutoK>,
+  /// `--auto-k`で試すグループの数の最大値
+  #[arg(long, default_value = "8")]
+  max_k: usize,
+  /// 画素をグループに分ける方法
+  #[arg(long, value_enum, default_value_t = Method::KMeans)]
+  method: Method,
+  /// `--method gmm`で、事後確率がこの値より小さい画素はどのグループにも入れない
+  #[arg(long, default_value = "0")]
+  posterior_threshold: f64,
+  /// `--method gmm`で求めた各グループの事後確率をMetaImageファイルに書き出す
+  #[arg(long)]
+  export_posteriors: bool,
+  /// k-means法で、全ての重心の移動量がこの値（HU）以下になったら収束したとみなす
+  #[arg(long, default_value = "0")]
+  tolerance: f64,
+  /// k-means法で重心を更新する回数の上限
+  #[arg(long, default_value = "100")]
+  max_iterations: usize,
+  /// CT値に加えて位置も使って分類するときの、x, y, z方向の重み
+  /// 位置は画像の端から端までを1とし、重みを掛けてCT値と同じ単位で扱う
+  /// 1つだけ与えた場合は全ての方向で同じ重みにする
+  #[arg(long, value_delimiter = ' ', num_args = 1..=3)]
+  spatial_weight: Option<Vec<f64>>,
+  /// 体の輪郭を求め、寝台と体の外の空気を除いてから分類する
+  #[arg(long)]
+  body_mask: bool,
+  /// 体の輪郭を求めるときのCT値のしきい値
+  #[arg(long, default_value_t = body_mask::BODY_THRESHOLD, allow_negative_numbers = true)]
+  body_threshold: i16,
+  /// 求めた体の輪郭をOBJファイルに書き出す
+  #[arg(long, requires = "body_mask")]
+  export_body_mask: bool,
+  /// DICOMファイルを同時に読み込む数
+  /// 与えなかった場合はCPUのスレッド数
+  #[arg(short, long)]
+  jobs: Option<usize>,
 }
 
 async fn init_logger() -> Result<()> {
@@ -135,47 +292,239 @@ impl Point {
   }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
-pub struct Data {
-  pub point: Point,
-  pub data: i16,
-}
-
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
 pub struct Center {
   pub point: Option<Point>,
   pub data: i16,
 }
 
-// [WIP]
-fn calc_distance(center: &Center, data: &Data) -> usize {
-  (center.data as usize).abs_diff(data.data as usize)
+/// x, y, zの順に与える値を3つの値にする
+/// 1つだけ与えた場合は全ての方向で同じ値にする
+fn per_axis<T: Copy>(v: &[T], name: &str) -> Result<[T; 3]> {
+  match v[..] {
+    [a] => Ok([a; 3]),
+    [x, y, z] => Ok([x, y, z]),
+    _ => Err(anyhow!("error: {name} takes 1 or 3 values")),
+  }
 }
 
-// [WIP]
-fn calc_center(lst: &[Data]) -> Option<Center> {
-  let len = lst.len();
-  if len == 0 {
-    None
-  } else {
-    let d = (lst.iter().map(|d| d.data as i64).sum::<i64>() / len as i64) as i16;
-    Some(Center {
-      point: None,
-      data: d,
+/// 混合ガウス分布で各画素をグループに分ける
+///
+/// `names`は各グループの部位の名前で、ログと事後確率のファイルの名前に使う
+/// 戻り値は事後確率が最も大きいグループの番号と、各成分の平均を重心としたもの
+async fn solve_gmm(
+  args: &Args,
+  volume: &Volume,
+  mask: Option<&Array3<bool>>,
+  init_center_lst: &[Center],
+  names: &[&str],
+) -> Result<(Array3<u8>, Vec<Center>)> {
+  let histogram = k_means::histogram(&volume.data, mask);
+  let init_means = init_center_lst
+    .iter()
+    .map(|center| center.data as f64)
+    .collect::<Vec<_>>();
+  let components = gmm::fit(&histogram, &init_means);
+  for (c, name) in components.iter().zip(names.iter()) {
+    info!(
+      "component [{name}] mean: {:.1}, variance: {:.1}, weight: {:.4}",
+      c.mean, c.variance, c.weight
+    );
+  }
+  let labels = k_means::label_by_value(&volume.data, mask, |value| {
+    gmm::classify(&components, value as f64, args.posterior_threshold)
+      .map(|i| i as u8)
+      .unwrap_or(filter::NO_GROUP)
+  });
+  if args.export_posteriors {
+    for (i, name) in names.iter().enumerate().take(components.len()) {
+      info!("[START] write posterior({name})");
+      // 0から255に拡大し、体の外側は0とする
+      let mut posterior = k_means::label_by_value(&volume.data, None, |value| {
+        (gmm::posterior(&components, value as f64)[i] * 255.0).round() as u8
+      });
+      if let Some(mask) = mask {
+        posterior.zip_mut_with(mask, |p, inside| {
+          if !*inside {
+            *p = 0;
+          }
+        });
+      }
+      let path = format!("{}_posterior_{name}.mha", &args.output);
+      write_metaimage::write_metaimage(Path::new(&path), &posterior, &volume.geometry).await?;
+      info!("[END] write posterior({name})");
+    }
+  }
+  // 画素がなく成分を当てはめられなかった場合は初期値のままとする
+  let center_lst = init_center_lst
+    .iter()
+    .enumerate()
+    .map(|(i, init)| match components.get(i) {
+      Some(c) => Center {
+        point: None,
+        data: c.mean.round() as i16,
+      },
+      None => *init,
     })
+    .collect();
+  Ok((labels, center_lst))
+}
+
+/// 多段階の大津の方法で求めたしきい値で各画素をグループに分ける
+///
+/// グループの数は`init_center_lst`の個数で、戻り値はグループの番号と、各グループのCT値の平均を重心としたもの
+/// 画素のないグループやしきい値が足りずにできなかったグループの重心は初期値のままとする
+fn solve_otsu(
+  volume: &Volume,
+  mask: Option<&Array3<bool>>,
+  init_center_lst: &[Center],
+) -> (Array3<u8>, Vec<Center>) {
+  let histogram = k_means::histogram(&volume.data, mask);
+  let thresholds = otsu::thresholds(&histogram, init_center_lst.len());
+  info!("thresholds: {thresholds:?}");
+  let mut summary_lst = vec![k_means::Summary::default(); init_center_lst.len()];
+  for (value, count) in histogram.iter() {
+    let summary = &mut summary_lst[otsu::class_of(&thresholds, *value)];
+    summary.count += count;
+    summary.sum += *value as i64 * *count as i64;
   }
+  let labels = k_means::label_by_value(&volume.data, mask, |value| {
+    otsu::class_of(&thresholds, value) as u8
+  });
+  let center_lst = summary_lst
+    .iter()
+    .zip(init_center_lst.iter())
+    .map(|(summary, init)| calc_center(summary).unwrap_or(*init))
+    .collect();
+  (labels, center_lst)
 }
 
-// 重心の近さが閾値以下になったら同じと見なす
-// [WIP]
-fn calc_eq(lst1: &[Data], lst2: &[Data]) -> bool {
-  let center_1 = calc_center(lst1);
-  let center_2 = calc_center(lst2);
-  match (center_1, center_2) {
-    (Some(d1), Some(d2)) => d1.data.abs_diff(d2.data) == 0,
-    (None, None) => true,
-    _ => false,
+/// marching cubesで生成した1つのグループのメッシュをOBJファイルに書き出す
+async fn write_obj(
+  path: &str,
+  geometry: &geometry::Geometry,
+  coordinate: CoordinateSystem,
+  obj_data: &marching_cubes::ObjData,
+) -> Result<()> {
+  let mut buf = File::create(path).await?;
+  let (v_lst, f_lst) = obj_data;
+  for p in v_lst.iter() {
+    let (x, y, z) = geometry.transform(coordinate, *p);
+    buf.write_all(format!("v {x} {y} {z}\n").as_bytes()).await?;
   }
+  let mut f_stream = tokio_stream::iter(f_lst);
+  while let Some((v1, v2, v3)) = f_stream.next().await {
+    buf
+      .write_all(format!("f {v1} {v2} {v3}\n").as_bytes())
+      .await?;
+  }
+  Ok(())
+}
+
+/// DICOMのフォルダからシリーズを選んで読み込む
+/// 戻り値は読み込んだデータと、スライスの抜け
+/// `--list-series`が与えられたときはシリーズの一覧を表示して`None`を返す
+async fn read_dicom_volume(args: &Args) -> Result<Option<(Volume, Vec<Gap>)>> {
+  let jobs = args.jobs.unwrap_or_else(|| {
+    std::thread::available_parallelism()
+      .map(|n| n.get())
+      .unwrap_or(1)
+  });
+  let file_lst = read_dicom::find_files(Path::new(&args.folder)).await?;
+  info!(
+    "[START] read headers ({} files, {jobs} jobs)",
+    file_lst.len()
+  );
+  let result_lst = read_dicom::map_parallel(file_lst, jobs, |path| {
+    let filename = path
+      .file_name()
+      .map(|name| name.to_string_lossy().to_string())
+      .unwrap_or_default();
+    let result = read_dicom::read_slices(&path, &filename);
+    (path, result)
+  })
+  .await?;
+  info!("[END] read headers");
+  let mut slice_lst = Vec::new();
+  let mut error_lst = Vec::new();
+  for (path, result) in result_lst {
+    match result {
+      Ok(slices) => slice_lst.extend(slices),
+      Err(err) => error_lst.push((path, err)),
+    }
+  }
+  if !error_lst.is_empty() {
+    warn!(
+      "{} files skipped:\n{}",
+      error_lst.len(),
+      read_dicom::error_report(&error_lst)
+    );
+  }
+  if slice_lst.is_empty() {
+    return Err(anyhow!("error: no DICOM image found in {}", args.folder));
+  }
+
+  let series_lst = series::group_series(slice_lst);
+  for (i, series) in series_lst.iter().enumerate() {
+    info!("series [{}] {series}", i + 1);
+  }
+  if args.list_series {
+    return Ok(None);
+  }
+  let series = series::select_series(series_lst, args.series.as_deref())?;
+  info!("selected series: {series}");
+  read_dicom::check_dimensions(&series.slices)?;
+
+  // 同じファイルに含まれるスライスはまとめて読み込む
+  let mut file_slice_lst: Vec<Vec<read_dicom::Slice>> = Vec::new();
+  for slice in series.slices {
+    match file_slice_lst.last_mut() {
+      Some(slices) if slices[0].path == slice.path => slices.push(slice),
+      _ => file_slice_lst.push(vec![slice]),
+    }
+  }
+  info!(
+    "[START] read pixels ({} files, {jobs} jobs)",
+    file_slice_lst.len()
+  );
+  let rescale = !args.no_rescale;
+  let result_lst = read_dicom::map_parallel(file_slice_lst, jobs, move |mut slices| {
+    let result = read_dicom::read_pixels(&mut slices, rescale);
+    (slices, result)
+  })
+  .await?;
+  info!("[END] read pixels");
+  let mut slice_lst = Vec::new();
+  let mut error_lst = Vec::new();
+  for (slices, result) in result_lst {
+    match result {
+      Ok(()) => slice_lst.extend(slices),
+      Err(err) => error_lst.push((slices[0].path.clone(), err)),
+    }
+  }
+  if !error_lst.is_empty() {
+    return Err(anyhow!(
+      "error: failed to read pixels of {} files:\n{}",
+      error_lst.len(),
+      read_dicom::error_report(&error_lst)
+    ));
+  }
+
+  info!("[START] sort slices");
+  let (slice_order, slice_lst) = read_dicom::sort_slices(slice_lst)?;
+  info!("[END] sort slices ({slice_order:?})");
+  let geometry = read_dicom::calc_geometry(slice_order, &slice_lst);
+  let gaps = read_dicom::find_gaps(slice_order, &slice_lst);
+  for gap in gaps.iter() {
+    warn!(
+      "{} slices missing between '{}' and '{}'",
+      gap.missing,
+      slice_lst[gap.after].filename,
+      slice_lst[gap.after + 1].filename
+    );
+  }
+  let volume = read_dicom::slices_to_volume(slice_lst, geometry)?;
+  Ok(Some((volume, gaps)))
 }
 
 #[tokio::main]
@@ -184,82 +533,131 @@ async fn main() -> Result<()> {
 
   init_logger().await?;
 
-  let mut data_lst = Vec::new();
-  let depth_re = Regex::new(r"[^\d]*(?<z>\d+)[^\d]*").unwrap();
-  let mut rows = 0;
-  let mut columns = 0;
-  let mut z_lst = Vec::new();
-  let mut files = fs::read_dir(args.folder).await?;
-  while let Some(file) = files.next_entry().await? {
-    let filename = file.file_name().into_string();
-    if filename.is_err() {
-      return Err(anyhow!("error: file name can not convert to string"));
-    }
-    let filename = filename.unwrap();
-
-    info!("[START] {filename}");
-
-    let depth_caps = depth_re
-      .captures(&filename)
-      .with_context(|| "error: file name")?;
-    let z = depth_caps
-      .name("z")
-      .map(|m| m.as_str().parse::<usize>().unwrap())
-      .with_context(|| "error")?;
-    z_lst.push(z);
-
-    let obj = open_file(file.path())?;
-    info!("[{filename}] open file");
-
-    // 標準DICOM画像タグセット一覧 - 医療用デジタル画像と通信タグ
-    // https://www.liberworks.co.jp/know/know_dicomTag.html
-    // タグの意味
-    // https://www.ihe-j.org/file2/n13/1.2_DICOM_Tanaka.pdf
-
-    // 行
-    rows = obj
-      .element(Tag(0x0028, 0x0010))?
-      .to_str()?
-      .parse::<usize>()?;
-    // 列
-    columns = obj
-      .element(Tag(0x0028, 0x0011))?
-      .to_str()?
-      .parse::<usize>()?;
-
-    let pixel_data = obj.decode_pixel_data()?;
-    let pixel_array = pixel_data.to_ndarray::<i16>()?;
-
-    for (i, d) in pixel_array.iter().enumerate() {
-      let x = i % rows;
-      let y = i / rows;
-
-      let mut color_data = *d;
-      if let Some(start_range) = &args.start_range {
-        if start_range[0] > x || start_range[1] > y || start_range[2] > z {
-          // 範囲外なので真っ黒にする
-          color_data = -1000
-        }
-      }
-      if let Some(end_range) = &args.end_range {
-        if end_range[0] < x || end_range[1] < y || end_range[2] < z {
-          // 範囲外なので真っ黒にする
-          color_data = -1000
-        }
-      }
-      let data = Data {
-        point: Point::new(x as u16, y as u16, z as u16),
-        data: color_data,
+  // 設定ファイルの誤りは画像を読み込む前に知らせる
+  let config_tissue_lst = match &args.tissues {
+    Some(path) => Some(tissue::read_tissues(Path::new(path)).await?),
+    None => None,
+  };
+
+  let path = Path::new(&args.folder);
+  let format = volume::detect_format(path);
+  let (volume, gaps) = match format {
+    InputFormat::Dicom => match read_dicom_volume(&args).await? {
+      Some(v) => v,
+      None => return Ok(()),
+    },
+    _ => {
+      info!("[START] read {format:?}");
+      let volume = match format {
+        InputFormat::Nifti => read_nifti::read_nifti(path, !args.no_rescale).await?,
+        InputFormat::MetaImage => read_metaimage::read_metaimage(path).await?,
+        _ => read_nrrd::read_nrrd(path).await?,
       };
-      data_lst.push(data);
+      info!("[END] read {format:?}");
+      (volume, Vec::new())
+    }
+  };
+
+  // ラベルの形で補えるのは、分類を元の格子で行う場合だけ
+  let aligned = resample::is_axis_aligned(&volume.geometry);
+  let cropped = args.start_range.is_some() || args.end_range.is_some();
+  let gap_fill = match args.fill_gaps.filter(|_| !gaps.is_empty()) {
+    Some(GapFill::Shape)
+      if !aligned
+        || cropped
+        || args.downsample.is_some()
+        || (args.isotropic.is_some() && !args.resample_back) =>
+    {
+      warn!("shape-based gap filling needs the original grid; intensity interpolation is used");
+      Some(GapFill::Intensity)
+    }
+    fill => fill,
+  };
+  let volume = if gap_fill == Some(GapFill::Intensity) {
+    info!("[START] fill gaps");
+    let volume = gap::fill_intensity(&volume, &gaps);
+    info!("[END] fill gaps");
+    volume
+  } else {
+    if !gaps.is_empty() && gap_fill.is_none() {
+      warn!("missing slices are not filled; use --fill-gaps to interpolate them");
+    }
+    volume
+  };
+  let volume = if aligned {
+    volume
+  } else {
+    warn!(
+      "image is tilted or oblique (direction: {:?}); resampled to an axis-aligned grid",
+      volume.geometry.direction
+    );
+    info!("[START] resample");
+    let volume = resample::align_to_axes(&volume);
+    info!("[END] resample");
+    volume
+  };
+  info!(
+    "size: {}x{}x{}",
+    volume.columns(),
+    volume.rows(),
+    volume.height()
+  );
+  info!("spacing: {:?}", volume.geometry.spacing);
+
+  // 解析を行う範囲だけを切り出す
+  let mut volume = if cropped {
+    let to_array = |v: &Vec<f64>| [v[0], v[1], v[2]];
+    let (start, end) = volume.crop_bounds(
+      args.range_coordinate,
+      args.start_range.as_ref().map(to_array),
+      args.end_range.as_ref().map(to_array),
+    )?;
+    let volume = volume.crop(start, end);
+    info!(
+      "cropped to {start:?}..={end:?} ({}x{}x{})",
+      volume.columns(),
+      volume.rows(),
+      volume.height()
+    );
+    volume
+  } else {
+    volume
+  };
+
+  if let Some(factor) = &args.downsample {
+    let factor = per_axis(factor, "--downsample")?;
+    if factor.contains(&0) {
+      return Err(anyhow!("error: --downsample must be positive"));
     }
+    info!("[START] downsample by {factor:?}");
+    volume = resample::downsample(&volume, factor);
+    info!(
+      "[END] downsample ({}x{}x{})",
+      volume.columns(),
+      volume.rows(),
+      volume.height()
+    );
+  }
 
-    info!("[END] {filename}");
+  // 等方化する前の格子の大きさと位置
+  let original_grid = (volume.data.dim(), volume.geometry);
+  if let Some(spacing) = args.isotropic {
+    if spacing <= 0.0 {
+      return Err(anyhow!("error: --isotropic must be positive"));
+    }
+    info!("[START] resample to {spacing}mm ({:?})", args.interpolation);
+    volume = resample::resample_isotropic(&volume, spacing, args.interpolation);
+    info!(
+      "[END] resample ({}x{}x{})",
+      volume.columns(),
+      volume.rows(),
+      volume.height()
+    );
   }
 
   // 初期値の重心
   // 概ねの場所を指定しておくことでコントロールしたい
-  let init_center_lst = if let Some(v) = args.init_colors {
+  let init_center_lst = if let Some(v) = &args.init_colors {
     v.iter()
       .map(|i| Center {
         point: None,
@@ -296,114 +694,275 @@ async fn main() -> Result<()> {
     ]
   };
 
+  // 体の外側を分類から除く
+  let mask = if args.body_mask {
+    info!("[START] body mask");
+    let mask = body_mask::body_mask(&volume.data, args.body_threshold);
+    info!(
+      "[END] body mask ({} / {} voxels)",
+      mask.iter().filter(|inside| **inside).count(),
+      mask.len()
+    );
+    if args.export_body_mask {
+      info!("[START] write body mask");
+      let labels = mask.mapv(|inside| inside as u8);
+      let obj_data_lst = marching_cubes::marching_cubes(2, &labels).await;
+      let path = format!("{}_body.obj", &args.output);
+      write_obj(&path, &volume.geometry, args.coordinate, &obj_data_lst[1]).await?;
+      info!("[END] write body mask");
+    }
+    Some(mask)
+  } else {
+    None
+  };
+
+  // k-means++で初期値を選び直す
+  let init_center_lst = if args.kmeans_pp || args.auto_k.is_some() {
+    let sample = k_means::sample(&volume.data, mask.as_ref(), k_means::SAMPLE_SIZE);
+    let k = match args.auto_k {
+      Some(method) => {
+        info!("[START] choose k ({method:?})");
+        let k = k_means::choose_k(&sample, args.max_k, method);
+        info!("[END] choose k: {k}");
+        k
+      }
+      None => init_center_lst.len(),
+    };
+    let seeds = k_means::kmeans_pp(&sample, k);
+    if seeds.len() < k {
+      warn!("only {} distinct values found for {k} groups", seeds.len());
+    }
+    info!("k-means++ seeds: {seeds:?}");
+    seeds
+      .into_iter()
+      .map(|data| Center { point: None, data })
+      .collect()
+  } else {
+    init_center_lst
+  };
+
+  // 部位の一覧
+  // 設定ファイルを与えなかった場合は番号を名前とする
+  let tissue_lst = config_tissue_lst.unwrap_or_else(|| {
+    init_center_lst
+      .iter()
+      .enumerate()
+      .map(|(i, center)| Tissue::numbered(i, center.data))
+      .collect()
+  });
+  let group_size = tissue_lst.len();
+  if group_size > filter::MAX_GROUPS {
+    return Err(anyhow!(
+      "error: too many groups (at most {})",
+      filter::MAX_GROUPS
+    ));
+  }
+
+  // CT値の範囲が固定された部位は分類から除き、残りの部位だけを分類する
+  let clustered = (0..group_size)
+    .filter(|i| tissue_lst[*i].range.is_none())
+    .collect::<Vec<_>>();
+  let fixed = (0..group_size)
+    .filter(|i| tissue_lst[*i].range.is_some())
+    .collect::<Vec<_>>();
+  let init_center_lst = clustered
+    .iter()
+    .filter_map(|i| tissue_lst[*i].initial)
+    .map(|data| Center { point: None, data })
+    .collect::<Vec<_>>();
+  let is_fixed = |value: i16| fixed.iter().any(|i| tissue_lst[*i].contains(value));
+  let cluster_mask = if fixed.is_empty() {
+    mask.clone()
+  } else {
+    let mut cluster_mask = volume.data.mapv(|value| !is_fixed(value));
+    if let Some(mask) = &mask {
+      cluster_mask.zip_mut_with(mask, |m, inside| *m &= *inside);
+    }
+    Some(cluster_mask)
+  };
+
   info!("[START] solve");
   // クラスタリング後の結果
-  let solved = k_means::solve(
-    calc_distance,
-    calc_center,
-    calc_eq,
-    init_center_lst,
-    &data_lst,
-  )
-  .await;
+  if args.method != Method::KMeans && args.spatial_weight.is_some() {
+    return Err(anyhow!(
+      "error: --spatial-weight can only be used with --method k-means"
+    ));
+  }
+  let mask_ref = cluster_mask.as_ref();
+  let (labels_raw, center_lst) = if init_center_lst.is_empty() {
+    // 全ての部位の範囲が固定されている
+    let mut labels = Array3::from_elem(volume.data.dim(), filter::NO_GROUP);
+    if let Some(mask) = &mask {
+      body_mask::apply_mask(&mut labels, mask);
+    }
+    (labels, Vec::new())
+  } else if args.method == Method::Gmm {
+    let names = clustered
+      .iter()
+      .map(|i| tissue_lst[*i].name.as_str())
+      .collect::<Vec<_>>();
+    solve_gmm(&args, &volume, mask_ref, &init_center_lst, &names).await?
+  } else if args.method == Method::Otsu {
+    solve_otsu(&volume, mask_ref, &init_center_lst)
+  } else {
+    if args.max_iterations == 0 {
+      return Err(anyhow!("error: --max-iterations must be positive"));
+    }
+    let options = k_means::Options {
+      tolerance: args.tolerance,
+      max_iterations: args.max_iterations,
+    };
+    let solution = if let Some(weight) = &args.spatial_weight {
+      // 位置も使う場合は画素ごとに距離を測る
+      let weight = per_axis(weight, "--spatial-weight")?;
+      let dim = [volume.columns(), volume.rows(), volume.height()];
+      let calc_distance =
+        |center: &Center, point, data| calc_distance(center, point, data, dim, weight);
+      k_means::solve(
+        calc_distance,
+        calc_spatial_center,
+        |c1: &Center, c2: &Center| match c2.point {
+          Some(point) => calc_distance(c1, point, c2.data) as f64,
+          None => calc_intensity_distance(c1, c2.data) as f64,
+        },
+        init_center_lst,
+        &volume.data,
+        mask_ref,
+        options,
+      )
+      .await
+    } else {
+      k_means::solve_histogram(
+        calc_intensity_distance,
+        calc_center,
+        |c1: &Center, c2: &Center| calc_intensity_distance(c1, c2.data) as f64,
+        init_center_lst,
+        &volume.data,
+        mask_ref,
+        options,
+      )
+      .await
+    };
+    if solution.converged {
+      info!("converged after {} iterations", solution.iterations);
+    } else {
+      warn!(
+        "not converged after {} iterations; raise --max-iterations or --tolerance",
+        solution.iterations
+      );
+    }
+    (solution.labels, solution.centers)
+  };
+  // 分類した部位の番号を全体の番号に直し、範囲が固定された部位を塗る
+  let mut labels_raw = labels_raw.mapv(|l| clustered.get(l as usize).map_or(l, |i| *i as u8));
+  if !fixed.is_empty() {
+    // 分類から除いた画素は`OUTSIDE`になっているので、体の内側かどうかは元のマスクで判断する
+    tissue::fill_fixed(&mut labels_raw, &volume.data, mask.as_ref(), &tissue_lst);
+  }
   info!("[END] solved");
+  for (i, center) in clustered.iter().zip(center_lst.iter()) {
+    let name = &tissue_lst[*i].name;
+    match center.point {
+      Some(p) => info!(
+        "center [{name}] {} at ({}, {}, {})",
+        center.data, p.x, p.y, p.z
+      ),
+      None => info!("center [{name}] {}", center.data),
+    }
+  }
+  // `--init-colors`にそのまま与えられる形で重心を表示する
+  info!(
+    "centers: {}",
+    center_lst
+      .iter()
+      .map(|center| center.data.to_string())
+      .collect::<Vec<_>>()
+      .join(" ")
+  );
 
-  let height: usize = *z_lst.iter().max().unwrap_or(&0) + 1;
-  let group_size = solved.len();
-
-  let point_lst = solved
-    .iter()
-    .map(|l| l.iter().map(|d| d.point).collect())
-    .collect::<Vec<Vec<Point>>>();
-  let block_data_raw = filter::gen_blocks(rows, columns, height, &point_lst);
   // ノイズ除去をする
-  let block_data = filter::opening_block(
-    rows,
-    columns,
-    height,
-    &block_data_raw,
-    group_size,
-    args.noise_removal,
-  )
-  .await;
+  let labels = filter::opening_block(&labels_raw, args.noise_removal).await;
   // 穴埋めをする
-  let block_data = filter::closing_block(
-    rows,
-    columns,
-    height,
-    &block_data,
-    group_size,
-    args.noise_removal,
-  )
-  .await;
+  let mut labels = filter::closing_block(&labels, args.noise_removal).await;
+  // 部位ごとにノイズ除去の回数が決められている場合はやり直す
+  for (i, tissue) in tissue_lst.iter().enumerate() {
+    if let Some(n) = tissue.noise_removal.filter(|n| *n != args.noise_removal) {
+      info!("[START] noise removal ({}, {n})", tissue.name);
+      filter::refilter_group(&labels_raw, &mut labels, i as u8, n).await;
+      info!("[END] noise removal ({})", tissue.name);
+    }
+  }
+  // 膨張で体の外側に広がった部分を戻す
+  if let Some(mask) = &mask {
+    body_mask::apply_mask(&mut labels, mask);
+  }
+
+  let (labels_raw, labels, geometry) = if args.resample_back {
+    let (dim, geometry) = original_grid;
+    info!("[START] resample labels back");
+    let from = volume.geometry.spacing;
+    let labels_raw = resample::resample_labels(&labels_raw, from, geometry.spacing, dim);
+    let labels = resample::resample_labels(&labels, from, geometry.spacing, dim);
+    info!("[END] resample labels back");
+    (labels_raw, labels, geometry)
+  } else {
+    (labels_raw, labels, volume.geometry)
+  };
+
+  let (labels_raw, labels) = if gap_fill == Some(GapFill::Shape) {
+    info!("[START] fill gaps");
+    let labels_raw = gap::fill_labels(&labels_raw, &gaps, group_size);
+    let labels = gap::fill_labels(&labels, &gaps, group_size);
+    info!("[END] fill gaps");
+    (labels_raw, labels)
+  } else {
+    (labels_raw, labels)
+  };
 
   if let Some(depth) = args.depth_img {
+    // 与えた深さは元の画像の断面の番号なので、切り出しや並べ直しをした後の格子の番号に直す
+    let z = geometry
+      .index_of(CoordinateSystem::Voxel, [0.0, 0.0, depth as f64])
+      .map(|p| p[2].round())
+      .filter(|z| *z >= 0.0 && (*z as usize) < labels.dim().0)
+      .ok_or_else(|| anyhow!("error: --depth-img {depth} is outside the analysed volume"))?
+      as usize;
+    let colors = tissue_lst.iter().map(|t| t.color).collect::<Vec<_>>();
     // 元データ
     info!("[START] generate raw img");
-    let mut data_raw_48 = vec![vec![]; group_size];
-    for xy in block_data_raw[depth].iter() {
-      for x in xy.iter() {
-        if let Some((point, group)) = &x {
-          let mut group = group.clone();
-          group.sort();
-          if let Some(g) = group.first() {
-            data_raw_48[*g].push(*point);
-          }
-        }
-      }
-    }
-    let img_48 = write_image::point_to_img(rows as u32, columns as u32, &data_raw_48).await;
-    img_48.save(format!("{depth}_raw.png"))?;
-    for (i, data) in data_raw_48.iter().enumerate() {
-      let img = write_image::point_to_img(rows as u32, columns as u32, &[data.clone()]).await;
-      img.save(format!("{depth}_raw_{i}.png"))?;
+    let slice = labels_raw.index_axis(Axis(0), z);
+    let img = write_image::labels_to_img(slice, None, &colors).await;
+    img.save(format!("{depth}_raw.png"))?;
+    for (i, tissue) in tissue_lst.iter().enumerate() {
+      let img = write_image::labels_to_img(slice, Some(i), &colors).await;
+      img.save(format!("{depth}_raw_{}.png", tissue.name))?;
     }
     info!("[END] generate raw img");
 
     // オープニング・クロージングした後
     info!("[START] generate oc img");
-    let mut data_48 = vec![vec![]; group_size];
-    for xy in block_data[depth].iter() {
-      for x in xy.iter() {
-        if let Some((point, group)) = &x {
-          let mut group = group.clone();
-          group.sort();
-          if let Some(g) = group.first() {
-            data_48[*g].push(*point);
-          }
-        }
-      }
-    }
-    let img_48 = write_image::point_to_img(rows as u32, columns as u32, &data_48).await;
-    img_48.save(format!("{depth}.png"))?;
-    for (i, data) in data_48.iter().enumerate() {
-      let img = write_image::point_to_img(rows as u32, columns as u32, &[data.clone()]).await;
-      img.save(format!("{depth}_{i}.png"))?;
+    let slice = labels.index_axis(Axis(0), z);
+    let img = write_image::labels_to_img(slice, None, &colors).await;
+    img.save(format!("{depth}.png"))?;
+    for (i, tissue) in tissue_lst.iter().enumerate() {
+      let img = write_image::labels_to_img(slice, Some(i), &colors).await;
+      img.save(format!("{depth}_{}.png", tissue.name))?;
     }
     info!("[End] generate oc img");
   }
 
   info!("[START] marching_cubes");
-  let obj_data_lst =
-    marching_cubes::marching_cubes(rows, columns, height, group_size, &block_data).await;
+  let obj_data_lst = marching_cubes::marching_cubes(group_size, &labels).await;
   let obj_data_iter = obj_data_lst.iter().enumerate();
   info!("[END] marching_cubes");
   let mut obj_data_stream = tokio_stream::iter(obj_data_iter);
   while let Some((i, obj_data)) = obj_data_stream.next().await {
-    if i != 0 {
-      info!("[START] write obj file({i})");
-      let mut buf = File::create(format!("{}_{i}.obj", &args.output)).await?;
-      let (v_lst, f_lst) = obj_data;
-      for (x, y, z) in v_lst.iter() {
-        buf.write_all(format!("v {x} {y} {z}\n").as_bytes()).await?;
-      }
-      let mut f_stream = tokio_stream::iter(f_lst);
-      while let Some((v1, v2, v3)) = f_stream.next().await {
-        buf
-          .write_all(format!("f {v1} {v2} {v3}\n").as_bytes())
-          .await?;
-      }
-      info!("[END] write obj file({i})");
+    let tissue = &tissue_lst[i];
+    if tissue.mesh {
+      info!("[START] write obj file({})", tissue.name);
+      let path = format!("{}_{}.obj", &args.output, tissue.name);
+      write_obj(&path, &geometry, args.coordinate, obj_data).await?;
+      info!("[END] write obj file({})", tissue.name);
     }
   }
 
@@ -1,5 +1,6 @@
-use crate::filter::{Block, GroupList};
+use crate::filter::NO_GROUP;
 use crate::Point;
+use ndarray::Array3;
 use tokio_stream::StreamExt;
 
 /// ```comment
@@ -292,16 +293,13 @@ static EDGE: [(f32, f32, f32); 12] = [
   (0.0, 1.0, 0.5),
 ];
 
-fn get_group(p: &Point, blocks: &Block<GroupList>) -> usize {
-  blocks
-    .get(p.z as usize)
-    .and_then(|xy| xy.get(p.y as usize).map(|x| x.get(p.x as usize)))
-    .flatten()
-    .and_then(|data_opt| {
-      data_opt
-        .clone()
-        .map(|(_, g_l)| *g_l.iter().min().unwrap_or(&0))
-    })
+/// 範囲外とどのグループにも属さない画素は0番目のグループとして扱う
+/// 体の外側の画素（`OUTSIDE`）はどのグループとも異なるものとして扱う
+fn get_group(p: &Point, labels: &Array3<u8>) -> usize {
+  labels
+    .get([p.z as usize, p.y as usize, p.x as usize])
+    .filter(|label| **label != NO_GROUP)
+    .map(|label| *label as usize)
     .unwrap_or(0)
 }
 
@@ -324,7 +322,7 @@ fn get_group(p: &Point, blocks: &Block<GroupList>) -> usize {
 /// ```
 ///
 #[rustfmt::skip]
-async fn get_group_lst(p: &Point, blocks: &Block<GroupList>) -> [usize; 8] {
+async fn get_group_lst(p: &Point, labels: &Array3<u8>) -> [usize; 8] {
   let mut v = [0; 8];
   let mut point_stream = tokio_stream::iter(vec![
     (0, *p),
@@ -337,7 +335,7 @@ async fn get_group_lst(p: &Point, blocks: &Block<GroupList>) -> [usize; 8] {
     (7, Point{y: p.y + 1, z: p.z + 1, .. *p})
   ]);
   while let Some((i, p)) = point_stream.next().await {
-    v[i] = get_group(&p, blocks)
+    v[i] = get_group(&p, labels)
   }
   v
 }
@@ -357,20 +355,18 @@ fn get_tri_table_index(group_size: usize, group_lst: &[usize; 8]) -> Vec<usize>
   vec
 }
 
-pub async fn marching_cubes(
-  rows: usize,
-  columns: usize,
-  height: usize,
-  group_size: usize,
-  block: &Block<GroupList>,
-) -> Vec<(Vec<(f32, f32, f32)>, Vec<(usize, usize, usize)>)> {
+/// 1つのグループのメッシュ（頂点のリストと、1始まりの頂点の番号で表した面のリスト）
+pub type ObjData = (Vec<(f32, f32, f32)>, Vec<(usize, usize, usize)>);
+
+pub async fn marching_cubes(group_size: usize, labels: &Array3<u8>) -> Vec<ObjData> {
+  let (height, rows, columns) = labels.dim();
   let mut lst = vec![(Vec::new(), Vec::new()); group_size];
   let mut v_index_lst = vec![0; group_size];
-  for x in 0..rows {
-    for y in 0..columns {
+  for x in 0..columns {
+    for y in 0..rows {
       for z in 0..height {
         let p = Point::new(x as u16, y as u16, z as u16);
-        let group_lst = get_group_lst(&p, block).await;
+        let group_lst = get_group_lst(&p, labels).await;
         let index_lst = get_tri_table_index(group_size, &group_lst);
         let mut tri_table_index_stream = tokio_stream::iter(index_lst.iter().enumerate());
         while let Some((i, tri_table_index)) = tri_table_index_stream.next().await {
@@ -0,0 +1,88 @@
+/// 大津の方法をクラスの数に広げ、クラス間分散が最大になるしきい値を求める
+///
+/// `histogram`はCT値と画素の数の組をCT値の小さい順に並べたもの
+/// 戻り値は`k - 1`個のしきい値で、`i`番目のクラスは`thresholds[i - 1]`より大きく`thresholds[i]`以下のCT値
+/// 異なるCT値が`k`個より少ない場合はしきい値も少なくなる
+pub fn thresholds(histogram: &[(i16, usize)], k: usize) -> Vec<i16> {
+  let n = histogram.len();
+  let k = k.min(n);
+  if k <= 1 {
+    return Vec::new();
+  }
+  // 累積和を使って区間の画素の数とCT値の和を求める
+  let mut count = vec![0.0; n + 1];
+  let mut sum = vec![0.0; n + 1];
+  for (i, (v, c)) in histogram.iter().enumerate() {
+    count[i + 1] = count[i] + *c as f64;
+    sum[i + 1] = sum[i] + *v as f64 * *c as f64;
+  }
+  // 区間[a, b)を1つのクラスとしたときの、クラス間分散に効く項
+  let score = |a: usize, b: usize| {
+    let w = count[b] - count[a];
+    if w > 0.0 {
+      (sum[b] - sum[a]).powi(2) / w
+    } else {
+      0.0
+    }
+  };
+  // best[j][i]は先頭のi個の値をj + 1個のクラスに分けたときの最大値
+  let mut best = vec![vec![f64::NEG_INFINITY; n + 1]; k];
+  let mut from = vec![vec![0; n + 1]; k];
+  for (i, item) in best[0].iter_mut().enumerate().skip(1) {
+    *item = score(0, i);
+  }
+  for j in 1..k {
+    for i in j + 1..=n {
+      for m in j..i {
+        let v = best[j - 1][m] + score(m, i);
+        if v > best[j][i] {
+          best[j][i] = v;
+          from[j][i] = m;
+        }
+      }
+    }
+  }
+  // 区切りを後ろから辿る
+  let mut v = Vec::new();
+  let mut i = n;
+  for j in (1..k).rev() {
+    i = from[j][i];
+    v.push(histogram[i - 1].0);
+  }
+  v.reverse();
+  v
+}
+
+/// CT値が属するクラスの番号
+pub fn class_of(thresholds: &[i16], value: i16) -> usize {
+  thresholds.partition_point(|t| *t < value)
+}
+
+#[cfg(test)]
+mod otsu_test {
+  use crate::otsu::*;
+
+  #[test]
+  fn check_thresholds() {
+    let histogram = [
+      (-1000, 50),
+      (-990, 40),
+      (-800, 30),
+      (-780, 30),
+      (30, 20),
+      (50, 25),
+    ];
+    assert_eq!(thresholds(&histogram, 3), vec![-990, -780]);
+    assert_eq!(thresholds(&histogram, 2), vec![-780]);
+    assert_eq!(thresholds(&histogram[..2], 5), vec![-1000]);
+  }
+
+  #[test]
+  fn check_class_of() {
+    let thresholds = [-990, -780];
+    assert_eq!(class_of(&thresholds, -1000), 0);
+    assert_eq!(class_of(&thresholds, -990), 0);
+    assert_eq!(class_of(&thresholds, -989), 1);
+    assert_eq!(class_of(&thresholds, 40), 2);
+  }
+}
@@ -0,0 +1,920 @@
+use crate::gap::{self, Gap};
+use crate::geometry::Geometry;
+use crate::volume::Volume;
+use anyhow::{anyhow, Result};
+use dicom::encoding::TransferSyntaxIndex;
+use dicom::object::{open_file, InMemDicomObject, OpenFileOptions, Tag};
+use dicom::transfer_syntax::TransferSyntaxRegistry;
+use dicom_pixeldata::{ConvertOptions, ModalityLutOption, PixelDecoder};
+use regex::Regex;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs;
+use tokio::sync::Semaphore;
+use tracing::*;
+
+/// 同じ位置にあるとみなすスライス間の距離
+const SLICE_EPSILON: f64 = 1e-3;
+
+/// スライスが属するシリーズの情報
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SeriesInfo {
+  /// SeriesInstanceUID (0020,000E)
+  pub uid: String,
+  /// SeriesDescription (0008,103E)
+  pub description: String,
+  /// Modality (0008,0060)
+  pub modality: String,
+  /// ConvolutionKernel (0018,1210)
+  pub kernel: String,
+  /// SliceThickness (0018,0050)
+  pub thickness: Option<f64>,
+}
+
+/// 1枚分のスライスのデータ
+#[derive(Debug, Clone, PartialEq)]
+pub struct Slice {
+  pub path: PathBuf,
+  pub filename: String,
+  /// マルチフレームのファイルの場合のフレーム番号（0始まり）
+  pub frame: Option<u32>,
+  pub series: SeriesInfo,
+  pub rows: usize,
+  pub columns: usize,
+  /// ImagePositionPatient (0020,0032)
+  pub position: Option<[f64; 3]>,
+  /// ImageOrientationPatient (0020,0037)
+  pub orientation: Option<[f64; 6]>,
+  /// InstanceNumber (0020,0013)
+  pub instance_number: Option<i64>,
+  /// PixelSpacing (0028,0030)
+  /// 行の間隔、列の間隔の順
+  pub pixel_spacing: Option<[f64; 2]>,
+  /// SpacingBetweenSlices (0018,0088)、無ければSliceThickness (0018,0050)
+  pub slice_spacing: Option<f64>,
+  /// RescaleSlope (0028,1053)とRescaleIntercept (0028,1052)
+  pub rescale: Option<(f64, f64)>,
+  /// 画素値
+  /// [`read_pixels`]を呼ぶまでは空
+  pub pixels: Vec<i16>,
+}
+
+/// スライスを並べる際に用いた情報
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SliceOrder {
+  /// ImagePositionPatientをスライスの法線に射影した値
+  Position,
+  /// InstanceNumber
+  InstanceNumber,
+  /// ファイル名に含まれる最初の数字
+  FileName,
+}
+
+fn read_multi_f64<const N: usize>(obj: &InMemDicomObject, tag: Tag) -> Option<[f64; N]> {
+  obj
+    .element_opt(tag)
+    .ok()
+    .flatten()
+    .and_then(|e| e.to_multi_float64().ok())
+    .and_then(|v| v.try_into().ok())
+}
+
+fn read_string(obj: &InMemDicomObject, tag: Tag) -> String {
+  obj
+    .element_opt(tag)
+    .ok()
+    .flatten()
+    .and_then(|e| e.to_str().ok().map(|s| s.trim().to_string()))
+    .unwrap_or_default()
+}
+
+fn read_f64(obj: &InMemDicomObject, tag: Tag) -> Option<f64> {
+  obj
+    .element_opt(tag)
+    .ok()
+    .flatten()
+    .and_then(|e| e.to_float64().ok())
+}
+
+/// 保存されている値をRescaleSlopeとRescaleInterceptを用いてCT値（HU）に変換する
+fn rescale_value(v: i32, slope: f64, intercept: f64) -> i16 {
+  (v as f64 * slope + intercept)
+    .round()
+    .clamp(i16::MIN as f64, i16::MAX as f64) as i16
+}
+
+fn is_dicomdir(path: &Path) -> bool {
+  path
+    .file_name()
+    .map(|name| name.eq_ignore_ascii_case("DICOMDIR"))
+    .unwrap_or(false)
+}
+
+/// DICOMDIRから参照されている画像ファイルのパスのリストを得る
+fn read_dicomdir(path: &Path) -> Result<Vec<PathBuf>> {
+  let obj = open_file(path)?;
+  let root = path.parent().unwrap_or(Path::new("."));
+  let mut v = Vec::new();
+  // DirectoryRecordSequence (0004,1220)
+  let records = obj.element(Tag(0x0004, 0x1220))?.items().unwrap_or(&[]);
+  for record in records.iter() {
+    // DirectoryRecordType (0004,1430)
+    let record_type = record
+      .element_opt(Tag(0x0004, 0x1430))
+      .ok()
+      .flatten()
+      .and_then(|e| e.to_str().ok().map(|s| s.trim().to_string()))
+      .unwrap_or_default();
+    if record_type != "IMAGE" {
+      continue;
+    }
+    // ReferencedFileID (0004,1500)
+    if let Some(file_id) = record
+      .element_opt(Tag(0x0004, 0x1500))
+      .ok()
+      .flatten()
+      .and_then(|e| e.to_multi_str().ok())
+    {
+      let mut file = root.to_path_buf();
+      for component in file_id.iter() {
+        file.push(component.trim());
+      }
+      v.push(file);
+    }
+  }
+  Ok(v)
+}
+
+/// 読み込むファイルのリストを得る
+///
+/// `path`がDICOMDIRであるか、直下にDICOMDIRがある場合はそこから参照されている画像ファイルを用いる
+/// そうでない場合はフォルダ以下を再帰的に探索して全てのファイルを返す
+pub async fn find_files(path: &Path) -> Result<Vec<PathBuf>> {
+  let dicomdir = if is_dicomdir(path) {
+    Some(path.to_path_buf())
+  } else {
+    let mut files = fs::read_dir(path).await?;
+    let mut dicomdir = None;
+    while let Some(file) = files.next_entry().await? {
+      if is_dicomdir(&file.path()) && file.file_type().await?.is_file() {
+        dicomdir = Some(file.path());
+      }
+    }
+    dicomdir
+  };
+  if let Some(dicomdir) = &dicomdir {
+    info!("read DICOMDIR: {}", dicomdir.display());
+    match read_dicomdir(dicomdir) {
+      Ok(v) if !v.is_empty() => return Ok(v),
+      Ok(_) => warn!("DICOMDIR has no image record; the folder is searched instead"),
+      Err(err) => warn!("DICOMDIR can not be read ({err}); the folder is searched instead"),
+    }
+  }
+
+  let mut v = Vec::new();
+  let mut dir_stack = vec![dicomdir
+    .and_then(|p| p.parent().map(|p| p.to_path_buf()))
+    .unwrap_or(path.to_path_buf())];
+  while let Some(dir) = dir_stack.pop() {
+    let mut files = fs::read_dir(&dir).await?;
+    while let Some(file) = files.next_entry().await? {
+      let file_type = file.file_type().await?;
+      if file_type.is_dir() {
+        dir_stack.push(file.path());
+      } else if file_type.is_file() && !is_dicomdir(&file.path()) {
+        v.push(file.path());
+      }
+    }
+  }
+  v.sort();
+  Ok(v)
+}
+
+fn read_rescale(obj: &InMemDicomObject) -> Option<(f64, f64)> {
+  // RescaleSlope (0028,1053)
+  let slope = read_f64(obj, Tag(0x0028, 0x1053));
+  // RescaleIntercept (0028,1052)
+  let intercept = read_f64(obj, Tag(0x0028, 0x1052));
+  if slope.is_none() && intercept.is_none() {
+    None
+  } else {
+    Some((slope.unwrap_or(1.0), intercept.unwrap_or(0.0)))
+  }
+}
+
+/// シーケンスの最初の項目を得る
+fn read_item(obj: &InMemDicomObject, tag: Tag) -> Option<&InMemDicomObject> {
+  obj
+    .element_opt(tag)
+    .ok()
+    .flatten()
+    .and_then(|e| e.items())
+    .and_then(|items| items.first())
+}
+
+/// 機能グループのシーケンスを探す
+/// フレームごとの機能グループにあればそれを優先し、無ければ共通の機能グループから探す
+fn functional_group<'a>(
+  per_frame: Option<&'a InMemDicomObject>,
+  shared: Option<&'a InMemDicomObject>,
+  tag: Tag,
+) -> Option<&'a InMemDicomObject> {
+  per_frame
+    .and_then(|item| read_item(item, tag))
+    .or_else(|| shared.and_then(|item| read_item(item, tag)))
+}
+
+/// DICOMファイルのヘッダを読み込み、スライスのリストを得る
+///
+/// 通常のファイルは1枚分のスライスになり、
+/// Enhanced CTのようなマルチフレームのファイルはフレームごとのスライスになる
+/// 画素値は読み込まないため、[`read_pixels`]で別途読み込む
+pub fn read_slices(path: &Path, filename: &str) -> Result<Vec<Slice>> {
+  let obj = OpenFileOptions::new()
+    .read_until(Tag(0x7FE0, 0x0010))
+    .open_file(path)?;
+  info!("[{filename}] open file");
+  slices_from_object(&obj, path, filename)
+}
+
+fn slices_from_object(obj: &InMemDicomObject, path: &Path, filename: &str) -> Result<Vec<Slice>> {
+  // 標準DICOM画像タグセット一覧 - 医療用デジタル画像と通信タグ
+  // https://www.liberworks.co.jp/know/know_dicomTag.html
+  // タグの意味
+  // https://www.ihe-j.org/file2/n13/1.2_DICOM_Tanaka.pdf
+
+  // 行
+  let rows = obj
+    .element(Tag(0x0028, 0x0010))?
+    .to_str()?
+    .parse::<usize>()?;
+  // 列
+  let columns = obj
+    .element(Tag(0x0028, 0x0011))?
+    .to_str()?
+    .parse::<usize>()?;
+
+  let series = SeriesInfo {
+    uid: read_string(obj, Tag(0x0020, 0x000E)),
+    description: read_string(obj, Tag(0x0008, 0x103E)),
+    modality: read_string(obj, Tag(0x0008, 0x0060)),
+    kernel: read_string(obj, Tag(0x0018, 0x1210)),
+    thickness: read_f64(obj, Tag(0x0018, 0x0050)),
+  };
+  let position = read_multi_f64::<3>(obj, Tag(0x0020, 0x0032));
+  let orientation = read_multi_f64::<6>(obj, Tag(0x0020, 0x0037));
+  let instance_number = obj
+    .element_opt(Tag(0x0020, 0x0013))
+    .ok()
+    .flatten()
+    .and_then(|e| e.to_int::<i64>().ok());
+  let pixel_spacing = read_multi_f64::<2>(obj, Tag(0x0028, 0x0030));
+  let slice_spacing = read_f64(obj, Tag(0x0018, 0x0088)).or(series.thickness);
+
+  let slice = Slice {
+    path: path.to_path_buf(),
+    filename: filename.to_string(),
+    frame: None,
+    series,
+    rows,
+    columns,
+    position,
+    orientation,
+    instance_number,
+    pixel_spacing,
+    slice_spacing,
+    rescale: read_rescale(obj),
+    pixels: Vec::new(),
+  };
+
+  // NumberOfFrames (0028,0008)
+  let number_of_frames = obj
+    .element_opt(Tag(0x0028, 0x0008))
+    .ok()
+    .flatten()
+    .and_then(|e| e.to_int::<u32>().ok())
+    .unwrap_or(1);
+  // PerFrameFunctionalGroupsSequence (5200,9230)
+  let per_frame_lst = obj
+    .element_opt(Tag(0x5200, 0x9230))
+    .ok()
+    .flatten()
+    .and_then(|e| e.items());
+  if number_of_frames <= 1 && per_frame_lst.is_none() {
+    return Ok(vec![slice]);
+  }
+  // SharedFunctionalGroupsSequence (5200,9229)
+  let shared = read_item(obj, Tag(0x5200, 0x9229));
+
+  let mut v = Vec::new();
+  for frame in 0..number_of_frames {
+    let per_frame = per_frame_lst.and_then(|items| items.get(frame as usize));
+    // PlanePositionSequence (0020,9113)
+    let plane_position = functional_group(per_frame, shared, Tag(0x0020, 0x9113));
+    // PlaneOrientationSequence (0020,9116)
+    let plane_orientation = functional_group(per_frame, shared, Tag(0x0020, 0x9116));
+    // PixelMeasuresSequence (0028,9110)
+    let pixel_measures = functional_group(per_frame, shared, Tag(0x0028, 0x9110));
+    // PixelValueTransformationSequence (0028,9145)
+    let transformation = functional_group(per_frame, shared, Tag(0x0028, 0x9145));
+
+    let thickness = pixel_measures.and_then(|item| read_f64(item, Tag(0x0018, 0x0050)));
+    let mut series = slice.series.clone();
+    series.thickness = series.thickness.or(thickness);
+    v.push(Slice {
+      filename: format!("{filename}[{frame}]"),
+      frame: Some(frame),
+      series,
+      position: plane_position
+        .and_then(|item| read_multi_f64::<3>(item, Tag(0x0020, 0x0032)))
+        .or(slice.position),
+      orientation: plane_orientation
+        .and_then(|item| read_multi_f64::<6>(item, Tag(0x0020, 0x0037)))
+        .or(slice.orientation),
+      // フレームの順番をInstanceNumberの代わりにする
+      instance_number: Some(frame as i64 + 1),
+      pixel_spacing: pixel_measures
+        .and_then(|item| read_multi_f64::<2>(item, Tag(0x0028, 0x0030)))
+        .or(slice.pixel_spacing),
+      slice_spacing: pixel_measures
+        .and_then(|item| read_f64(item, Tag(0x0018, 0x0088)))
+        .or(thickness)
+        .or(slice.slice_spacing),
+      rescale: transformation.and_then(read_rescale).or(slice.rescale),
+      ..slice.clone()
+    });
+  }
+  Ok(v)
+}
+
+/// 画素データを展開できなかったときに、転送構文の名前を含めたエラーにする
+fn decode_error(uid: &str, err: impl std::fmt::Display) -> anyhow::Error {
+  let uid = uid.trim_end_matches(['\0', ' ']);
+  let name = TransferSyntaxRegistry
+    .get(uid)
+    .map(|ts| ts.name())
+    .unwrap_or("unknown transfer syntax");
+  let hint = match uid {
+    // JPEG Baseline, Extended, Lossless
+    "1.2.840.10008.1.2.4.50"
+    | "1.2.840.10008.1.2.4.51"
+    | "1.2.840.10008.1.2.4.57"
+    | "1.2.840.10008.1.2.4.70"
+      if !cfg!(feature = "jpeg") =>
+    {
+      " (build with `--features jpeg` to read it)"
+    }
+    // RLE Lossless
+    "1.2.840.10008.1.2.5" if !cfg!(feature = "rle") => " (build with `--features rle` to read it)",
+    // JPEG 2000
+    "1.2.840.10008.1.2.4.90" | "1.2.840.10008.1.2.4.91" if !cfg!(feature = "jpeg2000") => {
+      " (build with `--features jpeg2000` to read it)"
+    }
+    // JPEG-LS
+    "1.2.840.10008.1.2.4.80" | "1.2.840.10008.1.2.4.81" => {
+      " (JPEG-LS is not supported; convert the files first)"
+    }
+    _ => "",
+  };
+  anyhow!("error: cannot decode pixel data of {name} ({uid}){hint}: {err}")
+}
+
+/// 同じファイルに含まれるスライスの画素値をまとめて読み込む
+///
+/// `rescale`が`true`のときは画素値をCT値（HU）に変換する
+pub fn read_pixels(slices: &mut [Slice], rescale: bool) -> Result<()> {
+  let path = match slices.first() {
+    Some(slice) => slice.path.clone(),
+    None => return Ok(()),
+  };
+  let obj = open_file(&path)?;
+  let transfer_syntax = obj.meta().transfer_syntax();
+  let pixel_data = obj
+    .decode_pixel_data()
+    .map_err(|err| decode_error(transfer_syntax, err))?;
+  // 保存されている値をそのまま取り出し、CT値への変換はここで行う
+  let options = ConvertOptions::new().with_modality_lut(ModalityLutOption::None);
+  for slice in slices.iter_mut() {
+    let stored = pixel_data
+      .to_vec_frame_with_options::<i32>(slice.frame.unwrap_or(0), &options)
+      .map_err(|err| decode_error(transfer_syntax, err))?;
+    if stored.len() != slice.rows * slice.columns {
+      return Err(anyhow!(
+        "error: '{}' has {} pixels but Rows x Columns is {}x{}",
+        slice.filename,
+        stored.len(),
+        slice.rows,
+        slice.columns
+      ));
+    }
+    let (slope, intercept) = if rescale {
+      slice.rescale.unwrap_or((1.0, 0.0))
+    } else {
+      (1.0, 0.0)
+    };
+    slice.pixels = stored
+      .iter()
+      .map(|v| rescale_value(*v, slope, intercept))
+      .collect();
+  }
+  Ok(())
+}
+
+/// `items`のそれぞれに`f`を適用する
+///
+/// 同時に処理するのは`jobs`個までで、結果は`items`と同じ順番で返す
+pub async fn map_parallel<T, R, F>(items: Vec<T>, jobs: usize, f: F) -> Result<Vec<R>>
+where
+  T: Send + 'static,
+  R: Send + 'static,
+  F: Fn(T) -> R + Send + Sync + 'static,
+{
+  let semaphore = Arc::new(Semaphore::new(jobs.max(1)));
+  let f = Arc::new(f);
+  let mut handles = Vec::with_capacity(items.len());
+  for item in items {
+    let permit = semaphore.clone().acquire_owned().await?;
+    let f = f.clone();
+    handles.push(tokio::task::spawn_blocking(move || {
+      let r = f(item);
+      drop(permit);
+      r
+    }));
+  }
+  let mut v = Vec::with_capacity(handles.len());
+  for handle in handles {
+    v.push(handle.await?);
+  }
+  Ok(v)
+}
+
+/// 読み込めなかったファイルとその理由を1つの文字列にまとめる
+pub fn error_report(errors: &[(PathBuf, anyhow::Error)]) -> String {
+  errors
+    .iter()
+    .map(|(path, err)| format!("  {}: {err}", path.display()))
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+
+/// 全てのスライスのRows・Columns・PixelSpacingが揃っているかを確認する
+///
+/// 最も多いスライスの値を基準にし、異なるスライスがあればそのファイル名を全てエラーとして返す
+pub fn check_dimensions(slices: &[Slice]) -> Result<()> {
+  let same = |s1: &Slice, s2: &Slice| {
+    s1.rows == s2.rows
+      && s1.columns == s2.columns
+      && match (s1.pixel_spacing, s2.pixel_spacing) {
+        (Some(p1), Some(p2)) => p1
+          .iter()
+          .zip(p2.iter())
+          .all(|(a, b)| (a - b).abs() < SLICE_EPSILON),
+        (None, None) => true,
+        _ => false,
+      }
+  };
+  let reference = match slices
+    .iter()
+    .max_by_key(|s1| slices.iter().filter(|s2| same(s1, s2)).count())
+  {
+    Some(s) => s,
+    None => return Ok(()),
+  };
+  let deviations = slices
+    .iter()
+    .filter(|s| !same(reference, s))
+    .map(|s| {
+      format!(
+        "'{}' ({}x{}, {:?})",
+        s.filename, s.rows, s.columns, s.pixel_spacing
+      )
+    })
+    .collect::<Vec<_>>();
+  if deviations.is_empty() {
+    Ok(())
+  } else {
+    Err(anyhow!(
+      "error: slices differ from Rows x Columns = {}x{}, PixelSpacing = {:?}: {}",
+      reference.rows,
+      reference.columns,
+      reference.pixel_spacing,
+      deviations.join(", ")
+    ))
+  }
+}
+
+/// ImageOrientationPatientの行方向と列方向の外積からスライスの法線を求める
+pub fn slice_normal(orientation: &[f64; 6]) -> [f64; 3] {
+  let r = &orientation[0..3];
+  let c = &orientation[3..6];
+  [
+    r[1] * c[2] - r[2] * c[1],
+    r[2] * c[0] - r[0] * c[2],
+    r[0] * c[1] - r[1] * c[0],
+  ]
+}
+
+fn position_keys(slices: &[Slice]) -> Option<Vec<f64>> {
+  let normal = slice_normal(&slices.first()?.orientation?);
+  slices
+    .iter()
+    .map(|s| {
+      s.position
+        .map(|p| p[0] * normal[0] + p[1] * normal[1] + p[2] * normal[2])
+    })
+    .collect()
+}
+
+fn instance_number_keys(slices: &[Slice]) -> Option<Vec<f64>> {
+  slices
+    .iter()
+    .map(|s| s.instance_number.map(|n| n as f64))
+    .collect()
+}
+
+fn filename_keys(slices: &[Slice]) -> Option<Vec<f64>> {
+  let depth_re = Regex::new(r"[^\d]*(?<z>\d+)[^\d]*").unwrap();
+  slices
+    .iter()
+    .map(|s| {
+      depth_re
+        .captures(&s.filename)
+        .and_then(|caps| caps.name("z"))
+        .and_then(|m| m.as_str().parse::<usize>().ok())
+        .map(|z| z as f64)
+    })
+    .collect()
+}
+
+/// スライスを体軸方向に並べ替える
+///
+/// ImagePositionPatientをスライスの法線に射影した値を優先し、
+/// それが無い場合はInstanceNumber、最後の手段としてファイル名に含まれる数字を用いる
+/// 並べ替えた後の添字がそのままz座標になる
+pub fn sort_slices(slices: Vec<Slice>) -> Result<(SliceOrder, Vec<Slice>)> {
+  let (order, keys) = if let Some(keys) = position_keys(&slices) {
+    (SliceOrder::Position, keys)
+  } else if let Some(keys) = instance_number_keys(&slices) {
+    warn!("ImagePositionPatient is missing; slices are ordered by InstanceNumber");
+    (SliceOrder::InstanceNumber, keys)
+  } else if let Some(keys) = filename_keys(&slices) {
+    warn!("ImagePositionPatient and InstanceNumber are missing; slices are ordered by file name");
+    (SliceOrder::FileName, keys)
+  } else {
+    return Err(anyhow!(
+      "error: slices can not be ordered by position, instance number or file name"
+    ));
+  };
+
+  let mut lst = keys.into_iter().zip(slices).collect::<Vec<_>>();
+  lst.sort_by(|(k1, _), (k2, _)| k1.total_cmp(k2));
+
+  for w in lst.windows(2) {
+    let (k1, s1) = &w[0];
+    let (k2, s2) = &w[1];
+    if (k2 - k1).abs() < SLICE_EPSILON {
+      return Err(anyhow!(
+        "error: slices collide: '{}' and '{}' have the same {order:?} ({k1})",
+        s1.filename,
+        s2.filename
+      ));
+    }
+  }
+
+  Ok((order, lst.into_iter().map(|(_, s)| s).collect()))
+}
+
+/// 並べ替えたスライスから画素の添字と患者座標との対応を求める
+///
+/// スライスの間隔はImagePositionPatientから求めた隣り合うスライスの距離の中央値を用い、
+/// 位置が分からない場合はSpacingBetweenSlicesかSliceThicknessを用いる
+pub fn calc_geometry(order: SliceOrder, slices: &[Slice]) -> Geometry {
+  let mut geometry = Geometry::default();
+  let first = match slices.first() {
+    Some(s) => s,
+    None => return geometry,
+  };
+
+  if let Some([row_spacing, column_spacing]) = first.pixel_spacing {
+    geometry.spacing[0] = column_spacing;
+    geometry.spacing[1] = row_spacing;
+  } else {
+    warn!("PixelSpacing is missing; 1mm is used");
+  }
+
+  let keys = if order == SliceOrder::Position {
+    position_keys(slices)
+  } else {
+    None
+  };
+  let mut diffs = keys
+    .map(|keys| keys.windows(2).map(|w| w[1] - w[0]).collect::<Vec<_>>())
+    .unwrap_or_default();
+  diffs.sort_by(|d1, d2| d1.total_cmp(d2));
+  if let Some(median) = diffs.get(diffs.len() / 2) {
+    geometry.spacing[2] = *median;
+    if diffs
+      .iter()
+      .any(|d| (d - median).abs() > SLICE_EPSILON * 10.0)
+    {
+      warn!("slice spacing is not uniform; {median}mm is used");
+    }
+  } else if let Some(spacing) = first.slice_spacing {
+    geometry.spacing[2] = spacing;
+  } else if slices.len() > 1 {
+    warn!("slice spacing is unknown; 1mm is used");
+  }
+
+  if let (Some(position), Some(orientation)) = (first.position, first.orientation) {
+    geometry.origin = position;
+    geometry.direction = [
+      [orientation[0], orientation[1], orientation[2]],
+      [orientation[3], orientation[4], orientation[5]],
+      slice_normal(&orientation),
+    ];
+    // ガントリーが傾いている場合は、スライスが並ぶ向きが法線と一致しない
+    let last = slices.last().and_then(|s| s.position);
+    if let (SliceOrder::Position, Some(last)) = (order, last) {
+      let stack = [0, 1, 2].map(|k| last[k] - position[k]);
+      let norm = stack.iter().map(|v| v * v).sum::<f64>().sqrt();
+      if norm > SLICE_EPSILON {
+        let stack = stack.map(|v| v / norm);
+        let normal = geometry.direction[2];
+        let cos = (0..3).map(|k| stack[k] * normal[k]).sum::<f64>();
+        if cos < 1.0 - SLICE_EPSILON {
+          warn!(
+            "gantry tilt of {:.2} degrees detected",
+            cos.clamp(-1.0, 1.0).acos().to_degrees()
+          );
+          geometry.direction[2] = stack;
+          geometry.spacing[2] /= cos;
+        }
+      }
+    }
+  }
+
+  geometry
+}
+
+/// 並べ替えたスライスの位置から抜けを探す
+/// 位置で並べ替えていない場合は探さない
+pub fn find_gaps(order: SliceOrder, slices: &[Slice]) -> Vec<Gap> {
+  if order != SliceOrder::Position {
+    return Vec::new();
+  }
+  position_keys(slices)
+    .map(|keys| gap::find_gaps(&keys))
+    .unwrap_or_default()
+}
+
+/// 並べ替えたスライスを1つのデータにまとめる
+pub fn slices_to_volume(slices: Vec<Slice>, geometry: Geometry) -> Result<Volume> {
+  let rows = slices.first().map(|s| s.rows).unwrap_or(0);
+  let columns = slices.first().map(|s| s.columns).unwrap_or(0);
+  let height = slices.len();
+  let data = slices.into_iter().flat_map(|s| s.pixels).collect();
+  Volume::new(rows, columns, height, data, geometry)
+}
+
+#[cfg(test)]
+mod read_dicom_test {
+  use crate::read_dicom::*;
+
+  fn slice(filename: &str, position: Option<[f64; 3]>, instance_number: Option<i64>) -> Slice {
+    Slice {
+      path: PathBuf::from(filename),
+      filename: filename.to_string(),
+      frame: None,
+      series: SeriesInfo::default(),
+      rows: 1,
+      columns: 1,
+      position,
+      orientation: position.map(|_| [1.0, 0.0, 0.0, 0.0, 1.0, 0.0]),
+      instance_number,
+      pixel_spacing: Some([0.5, 0.7]),
+      slice_spacing: Some(2.0),
+      rescale: None,
+      pixels: vec![0],
+    }
+  }
+
+  fn names(slices: &[Slice]) -> Vec<&str> {
+    slices.iter().map(|s| s.filename.as_str()).collect()
+  }
+
+  #[test]
+  fn check_rescale_value() {
+    assert_eq!(rescale_value(0, 1.0, -1024.0), -1024);
+    assert_eq!(rescale_value(1024, 1.0, -1024.0), 0);
+    assert_eq!(rescale_value(100, 0.5, -1000.0), -950);
+    assert_eq!(rescale_value(70000, 1.0, 0.0), i16::MAX);
+  }
+
+  #[test]
+  fn check_multi_frame() {
+    use dicom::core::value::DataSetSequence;
+    use dicom::core::{DataElement, PrimitiveValue, VR};
+    let element = |tag: Tag, vr: VR, v: &str| DataElement::new(tag, vr, PrimitiveValue::from(v));
+    let multi = |tag: Tag, v: &[f64]| {
+      DataElement::new(
+        tag,
+        VR::DS,
+        PrimitiveValue::F64(v.iter().copied().collect()),
+      )
+    };
+    let sequence = |tag: Tag, item: InMemDicomObject| {
+      DataElement::new(tag, VR::SQ, DataSetSequence::from(vec![item]))
+    };
+    let frame = |z: f64| {
+      InMemDicomObject::from_element_iter([sequence(
+        Tag(0x0020, 0x9113),
+        InMemDicomObject::from_element_iter([multi(Tag(0x0020, 0x0032), &[0.0, 0.0, z])]),
+      )])
+    };
+    let shared = InMemDicomObject::from_element_iter([
+      sequence(
+        Tag(0x0020, 0x9116),
+        InMemDicomObject::from_element_iter([multi(
+          Tag(0x0020, 0x0037),
+          &[1.0, 0.0, 0.0, 0.0, 1.0, 0.0],
+        )]),
+      ),
+      sequence(
+        Tag(0x0028, 0x9110),
+        InMemDicomObject::from_element_iter([
+          multi(Tag(0x0028, 0x0030), &[0.5, 0.5]),
+          element(Tag(0x0018, 0x0050), VR::DS, "1.25"),
+        ]),
+      ),
+      sequence(
+        Tag(0x0028, 0x9145),
+        InMemDicomObject::from_element_iter([
+          element(Tag(0x0028, 0x1052), VR::DS, "-1024"),
+          element(Tag(0x0028, 0x1053), VR::DS, "1"),
+        ]),
+      ),
+    ]);
+    let obj = InMemDicomObject::from_element_iter([
+      element(Tag(0x0028, 0x0010), VR::US, "2"),
+      element(Tag(0x0028, 0x0011), VR::US, "2"),
+      element(Tag(0x0028, 0x0008), VR::IS, "2"),
+      sequence(Tag(0x5200, 0x9229), shared),
+      DataElement::new(
+        Tag(0x5200, 0x9230),
+        VR::SQ,
+        DataSetSequence::from(vec![frame(-10.0), frame(-8.75)]),
+      ),
+    ]);
+    let slices = slices_from_object(&obj, Path::new("a"), "a").unwrap();
+    assert_eq!(slices.len(), 2);
+    assert_eq!(slices[1].frame, Some(1));
+    assert_eq!(slices[1].position, Some([0.0, 0.0, -8.75]));
+    assert_eq!(slices[1].orientation, Some([1.0, 0.0, 0.0, 0.0, 1.0, 0.0]));
+    assert_eq!(slices[1].pixel_spacing, Some([0.5, 0.5]));
+    assert_eq!(slices[1].slice_spacing, Some(1.25));
+    assert_eq!(slices[1].rescale, Some((1.0, -1024.0)));
+    assert_eq!(slices[1].series.thickness, Some(1.25));
+  }
+
+  #[test]
+  fn check_sort_by_position() {
+    let slices = vec![
+      slice("a", Some([0.0, 0.0, -5.0]), Some(1)),
+      slice("b", Some([0.0, 0.0, -7.5]), Some(3)),
+      slice("c", Some([0.0, 0.0, -2.5]), Some(2)),
+    ];
+    let (order, sorted) = sort_slices(slices).unwrap();
+    assert_eq!(order, SliceOrder::Position);
+    assert_eq!(names(&sorted), vec!["b", "a", "c"]);
+  }
+
+  #[test]
+  fn check_sort_by_instance_number() {
+    let slices = vec![
+      slice("a", None, Some(3)),
+      slice("b", Some([0.0, 0.0, 1.0]), Some(1)),
+      slice("c", None, Some(2)),
+    ];
+    let (order, sorted) = sort_slices(slices).unwrap();
+    assert_eq!(order, SliceOrder::InstanceNumber);
+    assert_eq!(names(&sorted), vec!["b", "c", "a"]);
+  }
+
+  #[test]
+  fn check_sort_by_filename() {
+    let slices = vec![
+      slice("IMG0010.dcm", None, None),
+      slice("IMG0002.dcm", None, Some(1)),
+      slice("IMG0003.dcm", None, None),
+    ];
+    let (order, sorted) = sort_slices(slices).unwrap();
+    assert_eq!(order, SliceOrder::FileName);
+    assert_eq!(
+      names(&sorted),
+      vec!["IMG0002.dcm", "IMG0003.dcm", "IMG0010.dcm"]
+    );
+  }
+
+  #[test]
+  fn check_geometry() {
+    let slices = vec![
+      slice("a", Some([-10.0, -20.0, -7.5]), Some(1)),
+      slice("b", Some([-10.0, -20.0, -5.0]), Some(2)),
+      slice("c", Some([-10.0, -20.0, -2.5]), Some(3)),
+    ];
+    let geometry = calc_geometry(SliceOrder::Position, &slices);
+    assert_eq!(geometry.spacing, [0.7, 0.5, 2.5]);
+    assert_eq!(geometry.origin, [-10.0, -20.0, -7.5]);
+    let slices = vec![slice("a", None, Some(1)), slice("b", None, Some(2))];
+    let geometry = calc_geometry(SliceOrder::InstanceNumber, &slices);
+    assert_eq!(geometry.spacing, [0.7, 0.5, 2.0]);
+  }
+
+  #[test]
+  fn check_geometry_gantry_tilt() {
+    // 列の向きが体軸方向に傾いている（ガントリーが30度傾いている）
+    let (sin, cos) = 30f64.to_radians().sin_cos();
+    let mut slices = vec![
+      slice("a", Some([0.0, 0.0, 0.0]), Some(1)),
+      slice("b", Some([0.0, 0.0, 2.0]), Some(2)),
+      slice("c", Some([0.0, 0.0, 4.0]), Some(3)),
+    ];
+    for s in slices.iter_mut() {
+      s.orientation = Some([1.0, 0.0, 0.0, 0.0, cos, -sin]);
+    }
+    let geometry = calc_geometry(SliceOrder::Position, &slices);
+    assert!((geometry.spacing[2] - 2.0).abs() < 1e-9);
+    assert!((geometry.direction[2][2] - 1.0).abs() < 1e-9);
+    assert!(geometry.direction[2][1].abs() < 1e-9);
+  }
+
+  #[test]
+  fn check_dimensions_deviation() {
+    let mut slices = vec![
+      slice("a", None, Some(1)),
+      slice("b", None, Some(2)),
+      slice("c", None, Some(3)),
+    ];
+    assert!(check_dimensions(&slices).is_ok());
+    slices[1].columns = 2;
+    let err = check_dimensions(&slices).unwrap_err().to_string();
+    assert!(err.contains("'b'"));
+    assert!(!err.contains("'a'"));
+    slices[1].columns = 1;
+    slices[2].pixel_spacing = Some([0.8, 0.8]);
+    assert!(check_dimensions(&slices).is_err());
+  }
+
+  #[test]
+  fn check_sort_collision() {
+    let slices = vec![
+      slice("a", Some([0.0, 0.0, 1.0]), Some(1)),
+      slice("b", Some([0.0, 0.0, 1.0]), Some(2)),
+    ];
+    assert!(sort_slices(slices).is_err());
+  }
+
+  #[test]
+  fn check_sort_no_key() {
+    let slices = vec![slice("a", None, None), slice("b", None, None)];
+    assert!(sort_slices(slices).is_err());
+  }
+
+  #[tokio::test]
+  async fn check_map_parallel() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let running = Arc::new(AtomicUsize::new(0));
+    let max_running = Arc::new(AtomicUsize::new(0));
+    let (r, m) = (running.clone(), max_running.clone());
+    let v = map_parallel((0..20).collect(), 3, move |i: u64| {
+      let n = r.fetch_add(1, Ordering::SeqCst) + 1;
+      m.fetch_max(n, Ordering::SeqCst);
+      std::thread::sleep(std::time::Duration::from_millis(20 - i));
+      r.fetch_sub(1, Ordering::SeqCst);
+      i * 2
+    })
+    .await
+    .unwrap();
+    assert_eq!(v, (0..20).map(|i| i * 2).collect::<Vec<_>>());
+    assert!(max_running.load(Ordering::SeqCst) <= 3);
+  }
+
+  #[test]
+  fn check_decode_error() {
+    let err = decode_error("1.2.840.10008.1.2.4.90\0", "unsupported").to_string();
+    assert!(err.contains("JPEG 2000"));
+    assert!(err.contains("1.2.840.10008.1.2.4.90)"));
+    let err = decode_error("1.2.840.10008.1.2.4.80", "unsupported").to_string();
+    assert!(err.contains("JPEG-LS is not supported"));
+    let err = decode_error("1.2.3", "unsupported").to_string();
+    assert!(err.contains("unknown transfer syntax (1.2.3)"));
+  }
+
+  #[test]
+  fn check_jpeg2000_registered() {
+    for uid in ["1.2.840.10008.1.2.4.90", "1.2.840.10008.1.2.4.91"] {
+      let ts = TransferSyntaxRegistry.get(uid).unwrap();
+      assert_eq!(ts.can_decode_all(), cfg!(feature = "jpeg2000"));
+    }
+  }
+}
@@ -0,0 +1,175 @@
+use crate::geometry::Geometry;
+use crate::volume::{decode_scalars, ScalarType, Volume};
+use anyhow::{anyhow, Context, Result};
+use flate2::read::ZlibDecoder;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+use tokio::fs;
+use tracing::*;
+
+fn scalar_type(element_type: &str) -> Result<ScalarType> {
+  let t = match element_type {
+    "MET_UCHAR" => ScalarType::U8,
+    "MET_CHAR" => ScalarType::I8,
+    "MET_USHORT" => ScalarType::U16,
+    "MET_SHORT" => ScalarType::I16,
+    "MET_UINT" => ScalarType::U32,
+    "MET_INT" => ScalarType::I32,
+    "MET_FLOAT" => ScalarType::F32,
+    "MET_DOUBLE" => ScalarType::F64,
+    _ => {
+      return Err(anyhow!(
+        "error: MetaImage ElementType {element_type} is not supported"
+      ))
+    }
+  };
+  Ok(t)
+}
+
+fn parse_f64_list(s: &str) -> Result<Vec<f64>> {
+  s.split_whitespace()
+    .map(|v| v.parse::<f64>().with_context(|| format!("error: '{v}'")))
+    .collect()
+}
+
+/// MetaImageのヘッダを読む
+/// 戻り値はキーと値の組と、ヘッダの終わりの位置
+/// ヘッダはElementDataFileで終わる
+fn parse_header(bytes: &[u8]) -> Result<(HashMap<String, String>, usize)> {
+  let mut header = HashMap::new();
+  let mut pos = 0;
+  while pos < bytes.len() {
+    let end = bytes[pos..]
+      .iter()
+      .position(|b| *b == b'\n')
+      .map(|i| pos + i + 1)
+      .unwrap_or(bytes.len());
+    let line = String::from_utf8_lossy(&bytes[pos..end]);
+    pos = end;
+    if let Some((key, value)) = line.split_once('=') {
+      let key = key.trim().to_string();
+      let is_last = key == "ElementDataFile";
+      header.insert(key, value.trim().to_string());
+      if is_last {
+        return Ok((header, pos));
+      }
+    }
+  }
+  Err(anyhow!("error: MetaImage header has no ElementDataFile"))
+}
+
+fn parse_metaimage(header: &HashMap<String, String>) -> Result<(Vec<usize>, Geometry)> {
+  let get = |key: &str| header.get(key).map(|s| s.as_str());
+  let ndims = get("NDims").unwrap_or("3").parse::<usize>()?;
+  if ndims != 3 {
+    return Err(anyhow!("error: MetaImage is not 3D (NDims = {ndims})"));
+  }
+  let dim_size = get("DimSize")
+    .ok_or_else(|| anyhow!("error: MetaImage header has no DimSize"))?
+    .split_whitespace()
+    .map(|v| v.parse::<usize>())
+    .collect::<Result<Vec<_>, _>>()?;
+  if dim_size.len() != 3 {
+    return Err(anyhow!("error: MetaImage DimSize must have 3 values"));
+  }
+
+  let mut geometry = Geometry::default();
+  if let Some(spacing) = get("ElementSpacing").or(get("ElementSize")) {
+    let spacing = parse_f64_list(spacing)?;
+    if spacing.len() == 3 {
+      geometry.spacing = [spacing[0], spacing[1], spacing[2]];
+    }
+  }
+  if let Some(origin) = get("Offset").or(get("Origin")).or(get("Position")) {
+    let origin = parse_f64_list(origin)?;
+    if origin.len() == 3 {
+      geometry.origin = [origin[0], origin[1], origin[2]];
+    }
+  }
+  // 3つずつ各軸の向きを表す（LPS）
+  if let Some(matrix) = get("TransformMatrix")
+    .or(get("Rotation"))
+    .or(get("Orientation"))
+  {
+    let matrix = parse_f64_list(matrix)?;
+    if matrix.len() == 9 {
+      for (axis, direction) in geometry.direction.iter_mut().enumerate() {
+        direction.copy_from_slice(&matrix[axis * 3..axis * 3 + 3]);
+      }
+    }
+  }
+  Ok((dim_size, geometry))
+}
+
+/// MetaImageファイル（.mhd, .mha）を読み込む
+///
+/// ElementDataFileがLOCALの場合はヘッダに続くデータを読み、
+/// そうでない場合はヘッダと同じフォルダにあるファイルを読む
+pub async fn read_metaimage(path: &Path) -> Result<Volume> {
+  let bytes = fs::read(path).await?;
+  let (header, header_end) = parse_header(&bytes)?;
+  let (dim_size, geometry) = parse_metaimage(&header)?;
+  let scalar_type = scalar_type(
+    header
+      .get("ElementType")
+      .ok_or_else(|| anyhow!("error: MetaImage header has no ElementType"))?,
+  )?;
+  let big_endian = header
+    .get("BinaryDataByteOrderMSB")
+    .or(header.get("ElementByteOrderMSB"))
+    .map(|v| v.eq_ignore_ascii_case("true"))
+    .unwrap_or(false);
+  let compressed = header
+    .get("CompressedData")
+    .map(|v| v.eq_ignore_ascii_case("true"))
+    .unwrap_or(false);
+
+  let data_file = &header["ElementDataFile"];
+  let raw = if data_file == "LOCAL" {
+    bytes[header_end..].to_vec()
+  } else if data_file == "LIST" || data_file.contains('%') {
+    return Err(anyhow!(
+      "error: MetaImage ElementDataFile '{data_file}' is not supported"
+    ));
+  } else {
+    let data_path = path.parent().unwrap_or(Path::new(".")).join(data_file);
+    info!("read MetaImage data: {}", data_path.display());
+    fs::read(&data_path).await?
+  };
+  let raw = if compressed {
+    let mut v = Vec::new();
+    ZlibDecoder::new(raw.as_slice()).read_to_end(&mut v)?;
+    v
+  } else {
+    raw
+  };
+
+  let [columns, rows, height] = [dim_size[0], dim_size[1], dim_size[2]];
+  let data = decode_scalars(
+    &raw,
+    scalar_type,
+    big_endian,
+    columns * rows * height,
+    1.0,
+    0.0,
+  )?;
+  Volume::new(rows, columns, height, data, geometry)
+}
+
+#[cfg(test)]
+mod read_metaimage_test {
+  use crate::read_metaimage::*;
+
+  #[test]
+  fn check_parse_metaimage() {
+    let bytes = b"ObjectType = Image\nNDims = 3\nBinaryData = True\nBinaryDataByteOrderMSB = False\nTransformMatrix = 1 0 0 0 1 0 0 0 1\nOffset = -195 -195 -378\nElementSpacing = 0.76 0.76 2.5\nDimSize = 512 512 121\nElementType = MET_SHORT\nElementDataFile = 1.3.6.1.raw\n";
+    let (header, end) = parse_header(bytes).unwrap();
+    assert_eq!(end, bytes.len());
+    assert_eq!(header["ElementDataFile"], "1.3.6.1.raw");
+    let (dim_size, geometry) = parse_metaimage(&header).unwrap();
+    assert_eq!(dim_size, vec![512, 512, 121]);
+    assert_eq!(geometry.spacing, [0.76, 0.76, 2.5]);
+    assert_eq!(geometry.origin, [-195.0, -195.0, -378.0]);
+  }
+}
@@ -0,0 +1,309 @@
+use crate::geometry::Geometry;
+use crate::volume::{decode_scalars, ScalarType, Volume};
+use anyhow::{anyhow, Result};
+use flate2::read::GzDecoder;
+use std::io::Read;
+use std::path::Path;
+use tokio::fs;
+use tracing::*;
+
+/// NIfTI-1のヘッダの大きさ
+const HEADER_SIZE: usize = 348;
+
+/// 先頭のバイト列がNIfTI-1のヘッダかどうかを判定する
+pub fn is_nifti_header(head: &[u8]) -> bool {
+  head.len() >= 4
+    && (head[..4] == (HEADER_SIZE as i32).to_le_bytes()
+      || head[..4] == (HEADER_SIZE as i32).to_be_bytes())
+}
+
+/// NIfTI-1ファイル（.nii, .nii.gz）を読み込む
+///
+/// `rescale`が`true`のときはscl_slopeとscl_interを用いて値を変換する
+pub async fn read_nifti(path: &Path, rescale: bool) -> Result<Volume> {
+  let raw = fs::read(path).await?;
+  let bytes = if raw.starts_with(&[0x1f, 0x8b]) {
+    let mut bytes = Vec::new();
+    GzDecoder::new(raw.as_slice()).read_to_end(&mut bytes)?;
+    bytes
+  } else {
+    raw
+  };
+  parse_nifti(&bytes, rescale)
+}
+
+struct Reader<'a> {
+  bytes: &'a [u8],
+  big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+  fn get<const N: usize>(&self, offset: usize) -> Result<[u8; N]> {
+    let mut b: [u8; N] = self
+      .bytes
+      .get(offset..offset + N)
+      .ok_or_else(|| anyhow!("error: NIfTI file is too short"))?
+      .try_into()?;
+    if self.big_endian != cfg!(target_endian = "big") {
+      b.reverse();
+    }
+    Ok(b)
+  }
+  fn i16(&self, offset: usize) -> Result<i16> {
+    Ok(i16::from_ne_bytes(self.get(offset)?))
+  }
+  fn i32(&self, offset: usize) -> Result<i32> {
+    Ok(i32::from_ne_bytes(self.get(offset)?))
+  }
+  fn f32(&self, offset: usize) -> Result<f32> {
+    Ok(f32::from_ne_bytes(self.get(offset)?))
+  }
+}
+
+/// datatypeに対応する画素値の型
+fn scalar_type(datatype: i16) -> Result<ScalarType> {
+  let t = match datatype {
+    // DT_UINT8
+    2 => ScalarType::U8,
+    // DT_INT16
+    4 => ScalarType::I16,
+    // DT_INT32
+    8 => ScalarType::I32,
+    // DT_FLOAT32
+    16 => ScalarType::F32,
+    // DT_FLOAT64
+    64 => ScalarType::F64,
+    // DT_INT8
+    256 => ScalarType::I8,
+    // DT_UINT16
+    512 => ScalarType::U16,
+    // DT_UINT32
+    768 => ScalarType::U32,
+    _ => return Err(anyhow!("error: NIfTI datatype {datatype} is not supported")),
+  };
+  Ok(t)
+}
+
+/// 列ベクトルが各軸の向きと大きさを表す3x4のアフィン行列（RAS）から、LPSのGeometryを作る
+fn affine_to_geometry(affine: [[f64; 4]; 3]) -> Geometry {
+  let mut geometry = Geometry::default();
+  for axis in 0..3 {
+    let column = affine.map(|row| row[axis]);
+    let norm = column.iter().map(|v| v * v).sum::<f64>().sqrt();
+    if norm > 0.0 {
+      geometry.spacing[axis] = norm;
+      // RASからLPSに変換する
+      geometry.direction[axis] = [-column[0] / norm, -column[1] / norm, column[2] / norm];
+    }
+  }
+  geometry.origin = [-affine[0][3], -affine[1][3], affine[2][3]];
+  geometry
+}
+
+/// quaternionとpixdimからアフィン行列を作る
+fn quaternion_to_affine(q: [f64; 3], offset: [f64; 3], pixdim: [f64; 4]) -> [[f64; 4]; 3] {
+  let [b, c, d] = q;
+  let a = (1.0 - (b * b + c * c + d * d)).max(0.0).sqrt();
+  let r = [
+    [
+      a * a + b * b - c * c - d * d,
+      2.0 * (b * c - a * d),
+      2.0 * (b * d + a * c),
+    ],
+    [
+      2.0 * (b * c + a * d),
+      a * a + c * c - b * b - d * d,
+      2.0 * (c * d - a * b),
+    ],
+    [
+      2.0 * (b * d - a * c),
+      2.0 * (c * d + a * b),
+      a * a + d * d - c * c - b * b,
+    ],
+  ];
+  // pixdim[0]はqfacで、z軸の向きを表す
+  let qfac = if pixdim[0] < 0.0 { -1.0 } else { 1.0 };
+  let scale = [pixdim[1], pixdim[2], pixdim[3] * qfac];
+  let mut affine = [[0.0; 4]; 3];
+  for (i, row) in affine.iter_mut().enumerate() {
+    for j in 0..3 {
+      row[j] = r[i][j] * scale[j];
+    }
+    row[3] = offset[i];
+  }
+  affine
+}
+
+fn parse_nifti(bytes: &[u8], rescale: bool) -> Result<Volume> {
+  let mut reader = Reader {
+    bytes,
+    big_endian: false,
+  };
+  if reader.i32(0)? != HEADER_SIZE as i32 {
+    reader.big_endian = true;
+    if reader.i32(0)? != HEADER_SIZE as i32 {
+      return Err(anyhow!("error: not a NIfTI-1 file"));
+    }
+  }
+  let magic = bytes.get(344..348).unwrap_or(&[]);
+  if magic != b"n+1\0" {
+    return Err(anyhow!(
+      "error: only single file NIfTI-1 (.nii, .nii.gz) is supported"
+    ));
+  }
+
+  let dim = (0..8)
+    .map(|i| reader.i16(40 + i * 2))
+    .collect::<Result<Vec<_>>>()?;
+  if dim[0] < 3 {
+    return Err(anyhow!(
+      "error: NIfTI image is not 3D (dim[0] = {})",
+      dim[0]
+    ));
+  }
+  if dim[0] > 3 && dim[4..=dim[0].min(7) as usize].iter().any(|d| *d > 1) {
+    warn!("NIfTI image has more than 3 dimensions; only the first volume is used");
+  }
+  let columns = dim[1].max(1) as usize;
+  let rows = dim[2].max(1) as usize;
+  let height = dim[3].max(1) as usize;
+  let scalar_type = scalar_type(reader.i16(70)?)?;
+  let pixdim = (0..4)
+    .map(|i| reader.f32(76 + i * 4).map(|v| v as f64))
+    .collect::<Result<Vec<_>>>()?;
+  let vox_offset = (reader.f32(108)? as usize).max(HEADER_SIZE);
+  let scl_slope = reader.f32(112)? as f64;
+  let scl_inter = reader.f32(116)? as f64;
+  let qform_code = reader.i16(252)?;
+  let sform_code = reader.i16(254)?;
+
+  let geometry = if sform_code > 0 {
+    let mut affine = [[0.0; 4]; 3];
+    for (i, row) in affine.iter_mut().enumerate() {
+      for (j, item) in row.iter_mut().enumerate() {
+        *item = reader.f32(280 + i * 16 + j * 4)? as f64;
+      }
+    }
+    affine_to_geometry(affine)
+  } else if qform_code > 0 {
+    let q = [
+      reader.f32(256)? as f64,
+      reader.f32(260)? as f64,
+      reader.f32(264)? as f64,
+    ];
+    let offset = [
+      reader.f32(268)? as f64,
+      reader.f32(272)? as f64,
+      reader.f32(276)? as f64,
+    ];
+    affine_to_geometry(quaternion_to_affine(
+      q,
+      offset,
+      [pixdim[0], pixdim[1], pixdim[2], pixdim[3]],
+    ))
+  } else {
+    warn!("NIfTI file has neither sform nor qform; pixdim is used as spacing");
+    Geometry {
+      spacing: [pixdim[1], pixdim[2], pixdim[3]].map(|v| if v > 0.0 { v } else { 1.0 }),
+      ..Default::default()
+    }
+  };
+
+  // scl_slopeが0のときは変換しない
+  let (slope, inter) = if rescale && scl_slope != 0.0 {
+    (scl_slope, scl_inter)
+  } else {
+    (1.0, 0.0)
+  };
+  let data = decode_scalars(
+    bytes.get(vox_offset..).unwrap_or(&[]),
+    scalar_type,
+    reader.big_endian,
+    columns * rows * height,
+    slope,
+    inter,
+  )?;
+
+  Volume::new(rows, columns, height, data, geometry)
+}
+
+#[cfg(test)]
+mod read_nifti_test {
+  use crate::read_nifti::*;
+
+  fn header(sform: bool) -> Vec<u8> {
+    let mut b = vec![0u8; 352];
+    let mut put = |offset: usize, v: &[u8]| b[offset..offset + v.len()].copy_from_slice(v);
+    put(0, &348i32.to_le_bytes());
+    for (i, d) in [3i16, 2, 3, 2, 1, 1, 1, 1].iter().enumerate() {
+      put(40 + i * 2, &d.to_le_bytes());
+    }
+    put(70, &4i16.to_le_bytes());
+    put(72, &16i16.to_le_bytes());
+    for (i, d) in [1.0f32, 0.5, 0.5, 2.0].iter().enumerate() {
+      put(76 + i * 4, &d.to_le_bytes());
+    }
+    put(108, &352f32.to_le_bytes());
+    put(112, &1f32.to_le_bytes());
+    put(116, &(-1024f32).to_le_bytes());
+    if sform {
+      put(254, &1i16.to_le_bytes());
+      let srow = [
+        [-0.5f32, 0.0, 0.0, 10.0],
+        [0.0, -0.5, 0.0, 20.0],
+        [0.0, 0.0, 2.0, -30.0],
+      ];
+      for (i, row) in srow.iter().enumerate() {
+        for (j, v) in row.iter().enumerate() {
+          put(280 + i * 16 + j * 4, &v.to_le_bytes());
+        }
+      }
+    }
+    put(344, b"n+1\0");
+    b
+  }
+
+  fn data() -> Vec<u8> {
+    (0..12i16).flat_map(|v| (v + 1024).to_le_bytes()).collect()
+  }
+
+  #[test]
+  fn check_parse_nifti() {
+    let bytes = [header(true), data()].concat();
+    let volume = parse_nifti(&bytes, true).unwrap();
+    assert_eq!(
+      (volume.columns(), volume.rows(), volume.height()),
+      (2, 3, 2)
+    );
+    assert_eq!(volume.data[[0, 0, 1]], 1);
+    assert_eq!(volume.data[[0, 1, 0]], 2);
+    assert_eq!(volume.data[[1, 2, 1]], 11);
+    assert_eq!(volume.geometry.spacing, [0.5, 0.5, 2.0]);
+    assert_eq!(volume.geometry.origin, [-10.0, -20.0, -30.0]);
+    assert_eq!(volume.geometry.direction[0], [1.0, -0.0, 0.0]);
+
+    let volume = parse_nifti(&bytes, false).unwrap();
+    assert_eq!(volume.data[[0, 0, 1]], 1025);
+  }
+
+  #[test]
+  fn check_parse_nifti_without_affine() {
+    let bytes = [header(false), data()].concat();
+    let volume = parse_nifti(&bytes, true).unwrap();
+    assert_eq!(volume.geometry.spacing, [0.5, 0.5, 2.0]);
+    assert_eq!(volume.geometry.origin, [0.0, 0.0, 0.0]);
+  }
+
+  #[test]
+  fn check_quaternion_identity() {
+    let affine = quaternion_to_affine([0.0, 0.0, 0.0], [1.0, 2.0, 3.0], [1.0, 0.5, 0.5, 2.0]);
+    assert_eq!(
+      affine,
+      [
+        [0.5, 0.0, 0.0, 1.0],
+        [0.0, 0.5, 0.0, 2.0],
+        [0.0, 0.0, 2.0, 3.0]
+      ]
+    );
+  }
+}
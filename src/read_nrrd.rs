@@ -0,0 +1,267 @@
+use crate::geometry::Geometry;
+use crate::volume::{decode_scalars, ScalarType, Volume};
+use anyhow::{anyhow, Context, Result};
+use flate2::read::GzDecoder;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+use tokio::fs;
+use tracing::*;
+
+fn scalar_type(t: &str) -> Result<ScalarType> {
+  let t = match t {
+    "uchar" | "unsigned char" | "uint8" | "uint8_t" => ScalarType::U8,
+    "signed char" | "int8" | "int8_t" => ScalarType::I8,
+    "ushort" | "unsigned short" | "unsigned short int" | "uint16" | "uint16_t" => ScalarType::U16,
+    "short" | "short int" | "signed short" | "signed short int" | "int16" | "int16_t" => {
+      ScalarType::I16
+    }
+    "uint" | "unsigned int" | "uint32" | "uint32_t" => ScalarType::U32,
+    "int" | "signed int" | "int32" | "int32_t" => ScalarType::I32,
+    "float" => ScalarType::F32,
+    "double" => ScalarType::F64,
+    _ => return Err(anyhow!("error: NRRD type '{t}' is not supported")),
+  };
+  Ok(t)
+}
+
+/// "(1,0,0)"のようなベクトルを読む
+/// "none"の場合は`None`を返す
+fn parse_vector(s: &str) -> Result<Option<Vec<f64>>> {
+  if s == "none" {
+    return Ok(None);
+  }
+  s.trim_matches(|c| c == '(' || c == ')')
+    .split(',')
+    .map(|v| {
+      v.trim()
+        .parse::<f64>()
+        .with_context(|| format!("error: '{s}'"))
+    })
+    .collect::<Result<Vec<_>>>()
+    .map(Some)
+}
+
+/// "(1,0,0) (0,1,0) (0,0,1)"のようなベクトルの並びを読む
+/// 括弧の中のカンマの後に空白があってもよい
+fn parse_vectors(s: &str) -> Result<Vec<Option<Vec<f64>>>> {
+  let mut v = Vec::new();
+  let mut rest = s.trim_start();
+  while !rest.is_empty() {
+    // 括弧で囲まれた部分か、空白までの部分（"none"）を1つのベクトルとする
+    let end = if rest.starts_with('(') {
+      rest
+        .find(')')
+        .map(|i| i + 1)
+        .with_context(|| format!("error: '{s}'"))?
+    } else {
+      rest.find(char::is_whitespace).unwrap_or(rest.len())
+    };
+    v.push(parse_vector(&rest[..end])?);
+    rest = rest[end..].trim_start();
+  }
+  Ok(v)
+}
+
+/// NRRDのヘッダを読む
+/// 戻り値はフィールドと、ヘッダの終わり（空行の次）の位置
+fn parse_header(bytes: &[u8]) -> Result<(HashMap<String, String>, usize)> {
+  if !bytes.starts_with(b"NRRD") {
+    return Err(anyhow!("error: not a NRRD file"));
+  }
+  let mut header = HashMap::new();
+  let mut pos = 0;
+  let mut first = true;
+  while pos < bytes.len() {
+    let end = bytes[pos..]
+      .iter()
+      .position(|b| *b == b'\n')
+      .map(|i| pos + i + 1)
+      .unwrap_or(bytes.len());
+    let line = String::from_utf8_lossy(&bytes[pos..end]);
+    let line = line.trim_end_matches(['\r', '\n']);
+    pos = end;
+    if first {
+      first = false;
+      continue;
+    }
+    if line.is_empty() {
+      break;
+    }
+    // コメントとキーと値の組（key:=value）は読み飛ばす
+    if line.starts_with('#') || line.contains(":=") {
+      continue;
+    }
+    if let Some((key, value)) = line.split_once(": ") {
+      header.insert(key.trim().to_string(), value.trim().to_string());
+    }
+  }
+  Ok((header, pos))
+}
+
+fn parse_nrrd(header: &HashMap<String, String>) -> Result<(Vec<usize>, Geometry)> {
+  let get = |key: &str| header.get(key).map(|s| s.as_str());
+  let dimension = get("dimension")
+    .ok_or_else(|| anyhow!("error: NRRD header has no dimension"))?
+    .parse::<usize>()?;
+  if dimension != 3 {
+    return Err(anyhow!(
+      "error: NRRD image is not 3D (dimension = {dimension})"
+    ));
+  }
+  let sizes = get("sizes")
+    .ok_or_else(|| anyhow!("error: NRRD header has no sizes"))?
+    .split_whitespace()
+    .map(|v| v.parse::<usize>())
+    .collect::<Result<Vec<_>, _>>()?;
+  if sizes.len() != 3 {
+    return Err(anyhow!("error: NRRD sizes must have 3 values"));
+  }
+
+  // RASなどの座標系ならLPSに変換するための符号
+  let sign = match get("space").unwrap_or("left-posterior-superior") {
+    "right-anterior-superior" | "RAS" => [-1.0, -1.0, 1.0],
+    "left-anterior-superior" | "LAS" => [1.0, -1.0, 1.0],
+    "left-posterior-superior" | "LPS" => [1.0, 1.0, 1.0],
+    space => {
+      warn!("NRRD space '{space}' is not supported; treated as LPS");
+      [1.0, 1.0, 1.0]
+    }
+  };
+
+  let mut geometry = Geometry::default();
+  if let Some(directions) = get("space directions") {
+    let directions = parse_vectors(directions)?;
+    if directions.len() != 3 {
+      return Err(anyhow!("error: NRRD space directions must have 3 vectors"));
+    }
+    for (axis, direction) in directions.iter().enumerate() {
+      let Some(direction) = direction.as_ref().filter(|d| d.len() == 3) else {
+        continue;
+      };
+      let norm = direction.iter().map(|v| v * v).sum::<f64>().sqrt();
+      if norm > 0.0 {
+        geometry.spacing[axis] = norm;
+        for k in 0..3 {
+          geometry.direction[axis][k] = sign[k] * direction[k] / norm;
+        }
+      }
+    }
+  } else if let Some(spacings) = get("spacings") {
+    for (axis, v) in spacings.split_whitespace().take(3).enumerate() {
+      if let Ok(v) = v.parse::<f64>() {
+        if v.is_finite() && v > 0.0 {
+          geometry.spacing[axis] = v;
+        }
+      }
+    }
+  }
+  if let Some(origin) = get("space origin") {
+    if let Some(origin) = parse_vector(origin)?.filter(|o| o.len() == 3) {
+      for k in 0..3 {
+        geometry.origin[k] = sign[k] * origin[k];
+      }
+    }
+  }
+  Ok((sizes, geometry))
+}
+
+/// NRRDファイル（.nrrd, .nhdr）を読み込む
+///
+/// encodingはraw, gzip, asciiに対応する
+/// data fileがある場合はヘッダと同じフォルダにあるファイルを読む
+pub async fn read_nrrd(path: &Path) -> Result<Volume> {
+  let bytes = fs::read(path).await?;
+  let (header, header_end) = parse_header(&bytes)?;
+  let (sizes, geometry) = parse_nrrd(&header)?;
+  let scalar_type = scalar_type(
+    header
+      .get("type")
+      .ok_or_else(|| anyhow!("error: NRRD header has no type"))?,
+  )?;
+  let big_endian = header.get("endian").map(|e| e == "big").unwrap_or(false);
+
+  let raw = match header.get("data file").or(header.get("datafile")) {
+    Some(data_file) => {
+      if data_file.starts_with("LIST") || data_file.contains('%') {
+        return Err(anyhow!(
+          "error: NRRD data file '{data_file}' is not supported"
+        ));
+      }
+      let data_path = path.parent().unwrap_or(Path::new(".")).join(data_file);
+      info!("read NRRD data: {}", data_path.display());
+      fs::read(&data_path).await?
+    }
+    None => bytes[header_end..].to_vec(),
+  };
+
+  let [columns, rows, height] = [sizes[0], sizes[1], sizes[2]];
+  let count = columns * rows * height;
+  let encoding = header.get("encoding").map(|s| s.as_str()).unwrap_or("raw");
+  let data = match encoding {
+    "raw" => decode_scalars(&raw, scalar_type, big_endian, count, 1.0, 0.0)?,
+    "gzip" | "gz" => {
+      let mut v = Vec::new();
+      GzDecoder::new(raw.as_slice()).read_to_end(&mut v)?;
+      decode_scalars(&v, scalar_type, big_endian, count, 1.0, 0.0)?
+    }
+    "ascii" | "txt" | "text" => {
+      let v = String::from_utf8_lossy(&raw)
+        .split_whitespace()
+        .take(count)
+        .map(|v| {
+          v.parse::<f64>()
+            .map(|v| v.round().clamp(-32768.0, 32767.0) as i16)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+      if v.len() != count {
+        return Err(anyhow!(
+          "error: NRRD data has {} values, expected {count}",
+          v.len()
+        ));
+      }
+      v
+    }
+    _ => {
+      return Err(anyhow!(
+        "error: NRRD encoding '{encoding}' is not supported"
+      ))
+    }
+  };
+
+  Volume::new(rows, columns, height, data, geometry)
+}
+
+#[cfg(test)]
+mod read_nrrd_test {
+  use crate::read_nrrd::*;
+
+  #[test]
+  fn check_parse_nrrd() {
+    let bytes = b"NRRD0004\n# comment\ntype: short\ndimension: 3\nspace: right-anterior-superior\nsizes: 512 512 121\nspace directions: (-0.76,0,0) (0,-0.76,0) (0,0,2.5)\nkinds: domain domain domain\nendian: little\nencoding: raw\nspace origin: (195,195,-378)\n\n\x00\x01";
+    let (header, end) = parse_header(bytes).unwrap();
+    assert_eq!(end, bytes.len() - 2);
+    assert_eq!(header["type"], "short");
+    let (sizes, geometry) = parse_nrrd(&header).unwrap();
+    assert_eq!(sizes, vec![512, 512, 121]);
+    assert_eq!(geometry.spacing, [0.76, 0.76, 2.5]);
+    assert_eq!(geometry.origin, [-195.0, -195.0, -378.0]);
+    assert_eq!(geometry.direction[0], [1.0, 0.0, 0.0]);
+    assert_eq!(geometry.direction[1], [0.0, 1.0, 0.0]);
+  }
+
+  #[test]
+  fn check_parse_vectors() {
+    let v = parse_vectors("(1, 0, 0) (0, 1, 0)  none (0,0,2.5)").unwrap();
+    assert_eq!(
+      v,
+      vec![
+        Some(vec![1.0, 0.0, 0.0]),
+        Some(vec![0.0, 1.0, 0.0]),
+        None,
+        Some(vec![0.0, 0.0, 2.5])
+      ]
+    );
+    assert!(parse_vectors("(1, 0, 0").is_err());
+  }
+}
@@ -0,0 +1,510 @@
+use crate::geometry::Geometry;
+use crate::volume::Volume;
+use clap::ValueEnum;
+use ndarray::{s, Array3, Axis};
+
+/// 向きが座標軸と一致しているとみなす誤差
+const ALIGN_EPSILON: f64 = 1e-3;
+
+/// 画像の範囲外の画素に与えるCT値（空気）
+pub const OUTSIDE_VALUE: i16 = -1000;
+
+/// 各軸に最も近い座標軸の番号と向き
+/// 2つ以上の軸が同じ座標軸に近い場合は`None`を返す
+fn nearest_axes(geometry: &Geometry) -> Option<[(usize, f64); 3]> {
+  let axes = geometry.direction.map(|d| {
+    let k = (0..3)
+      .max_by(|k1, k2| d[*k1].abs().total_cmp(&d[*k2].abs()))
+      .unwrap();
+    (k, d[k].signum())
+  });
+  if axes[0].0 == axes[1].0 || axes[1].0 == axes[2].0 || axes[0].0 == axes[2].0 {
+    None
+  } else {
+    Some(axes)
+  }
+}
+
+/// 各軸の向きが患者座標系の座標軸と一致しているかどうか
+pub fn is_axis_aligned(geometry: &Geometry) -> bool {
+  geometry
+    .direction
+    .iter()
+    .all(|d| d.iter().any(|v| (v.abs() - 1.0).abs() < ALIGN_EPSILON))
+}
+
+/// 3x3行列の逆行列
+pub fn inverse(m: [[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+  let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+    - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+    + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+  if det.abs() < f64::EPSILON {
+    return None;
+  }
+  let mut inv = [[0.0; 3]; 3];
+  for (i, row) in inv.iter_mut().enumerate() {
+    for (j, item) in row.iter_mut().enumerate() {
+      let (a, b) = ((j + 1) % 3, (j + 2) % 3);
+      let (c, d) = ((i + 1) % 3, (i + 2) % 3);
+      *item = (m[a][c] * m[b][d] - m[a][d] * m[b][c]) / det;
+    }
+  }
+  Some(inv)
+}
+
+/// 添字から患者座標への変換行列（列が各軸の向きと大きさ）
+pub fn index_matrix(geometry: &Geometry) -> [[f64; 3]; 3] {
+  let mut m = [[0.0; 3]; 3];
+  for (k, row) in m.iter_mut().enumerate() {
+    for (axis, item) in row.iter_mut().enumerate() {
+      *item = geometry.spacing[axis] * geometry.direction[axis][k];
+    }
+  }
+  m
+}
+
+/// 実数の添字の位置のCT値を周囲8画素から線形補間する
+/// 範囲外の場合は`None`を返す
+pub fn trilinear(data: &Array3<i16>, x: f64, y: f64, z: f64) -> Option<f64> {
+  let (height, rows, columns) = data.dim();
+  let inside = |v: f64, n: usize| v > -ALIGN_EPSILON && v < (n - 1) as f64 + ALIGN_EPSILON;
+  if !(inside(x, columns) && inside(y, rows) && inside(z, height)) {
+    return None;
+  }
+  let base = |v: f64, n: usize| (v.floor().max(0.0) as usize).min(n.saturating_sub(2));
+  let (x0, y0, z0) = (base(x, columns), base(y, rows), base(z, height));
+  let (fx, fy, fz) = (x - x0 as f64, y - y0 as f64, z - z0 as f64);
+  let mut v = 0.0;
+  for (dz, wz) in [(0, 1.0 - fz), (1, fz)] {
+    for (dy, wy) in [(0, 1.0 - fy), (1, fy)] {
+      for (dx, wx) in [(0, 1.0 - fx), (1, fx)] {
+        let w = wx * wy * wz;
+        if w != 0.0 {
+          v += w
+            * data[[
+              (z0 + dz).min(height - 1),
+              (y0 + dy).min(rows - 1),
+              (x0 + dx).min(columns - 1),
+            ]] as f64;
+        }
+      }
+    }
+  }
+  Some(v)
+}
+
+/// 傾いている画像を、患者座標系の座標軸に沿った格子に線形補間で並べ直す
+///
+/// 各軸は最も近い座標軸に揃え、画素の間隔はその座標軸へ射影した長さにする
+/// 元の画像の範囲外になる画素は`OUTSIDE_VALUE`とする
+pub fn align_to_axes(volume: &Volume) -> Volume {
+  let geometry = &volume.geometry;
+  let axes = nearest_axes(geometry).unwrap_or([(0, 1.0), (1, 1.0), (2, 1.0)]);
+  let mut aligned = Geometry::default();
+  for (axis, (k, sign)) in axes.iter().enumerate() {
+    aligned.direction[axis] = [0.0; 3];
+    aligned.direction[axis][*k] = *sign;
+    let projected = (geometry.spacing[axis] * geometry.direction[axis][*k]).abs();
+    aligned.spacing[axis] = if projected > ALIGN_EPSILON {
+      projected
+    } else {
+      geometry.spacing[axis]
+    };
+  }
+
+  // 元の画像の8つの角を新しい格子の添字で表し、範囲を求める
+  let dim = [volume.columns(), volume.rows(), volume.height()];
+  let to_patient = index_matrix(geometry);
+  let aligned_matrix = index_matrix(&aligned);
+  let to_aligned = inverse(aligned_matrix).unwrap();
+  let mut lower = [f64::INFINITY; 3];
+  let mut upper = [f64::NEG_INFINITY; 3];
+  for corner in 0..8 {
+    let index = [0, 1, 2].map(|axis| {
+      if corner & (1 << axis) == 0 {
+        0.0
+      } else {
+        dim[axis].saturating_sub(1) as f64
+      }
+    });
+    let p = [0, 1, 2].map(|k| (0..3).map(|j| to_patient[k][j] * index[j]).sum::<f64>());
+    for axis in 0..3 {
+      let v = (0..3).map(|k| to_aligned[axis][k] * p[k]).sum::<f64>();
+      lower[axis] = lower[axis].min(v);
+      upper[axis] = upper[axis].max(v);
+    }
+  }
+  let size =
+    [0, 1, 2].map(|axis| ((upper[axis] - lower[axis]) + ALIGN_EPSILON).floor() as usize + 1);
+  for (k, item) in aligned.origin.iter_mut().enumerate() {
+    *item = geometry.origin[k] + (0..3).map(|j| aligned_matrix[k][j] * lower[j]).sum::<f64>();
+  }
+
+  // 新しい格子の添字から元の画像の添字への変換
+  let from_patient = inverse(to_patient).unwrap_or(to_aligned);
+  let mut m = [[0.0; 3]; 3];
+  for (i, row) in m.iter_mut().enumerate() {
+    for (j, item) in row.iter_mut().enumerate() {
+      *item = (0..3)
+        .map(|k| from_patient[i][k] * aligned_matrix[k][j])
+        .sum::<f64>();
+    }
+  }
+  let offset = [0, 1, 2].map(|i| (0..3).map(|j| m[i][j] * lower[j]).sum::<f64>());
+
+  let mut data = Array3::from_elem((size[2], size[1], size[0]), OUTSIDE_VALUE);
+  for ((z, y, x), v) in data.indexed_iter_mut() {
+    let index = [x as f64, y as f64, z as f64];
+    let src = [0, 1, 2].map(|i| offset[i] + (0..3).map(|j| m[i][j] * index[j]).sum::<f64>());
+    if let Some(value) = trilinear(&volume.data, src[0], src[1], src[2]) {
+      *v = value.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+    }
+  }
+  Volume {
+    data,
+    geometry: aligned,
+  }
+}
+
+/// 等方化するときの補間の方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Interpolation {
+  /// 最近傍補間
+  Nearest,
+  /// 線形補間
+  Trilinear,
+  /// 3次Bスプライン補間
+  Bspline,
+}
+
+/// 間隔`from`で並んだ`n`個の画素を間隔`to`で並べ直したときの個数
+fn resampled_len(n: usize, from: f64, to: f64) -> usize {
+  if n == 0 {
+    return 0;
+  }
+  (((n - 1) as f64 * from / to) + ALIGN_EPSILON).floor() as usize + 1
+}
+
+/// 境界で折り返した添字
+fn mirror(i: isize, n: usize) -> usize {
+  if n == 1 {
+    return 0;
+  }
+  let period = 2 * (n as isize - 1);
+  let i = i.rem_euclid(period);
+  if i < n as isize {
+    i as usize
+  } else {
+    (period - i) as usize
+  }
+}
+
+/// 3次Bスプラインの係数を求める（境界は折り返し）
+///
+/// Unser, M. "Splines: a perfect fit for signal and image processing" (1999)
+fn bspline_coefficients(line: &mut [f32]) {
+  let n = line.len();
+  if n < 2 {
+    return;
+  }
+  let z = 3f64.sqrt() - 2.0;
+  let lambda = (1.0 - z) * (1.0 - 1.0 / z);
+  let mut c = line.iter().map(|v| *v as f64 * lambda).collect::<Vec<_>>();
+  // 因果的フィルタの初期値
+  let horizon = (f64::EPSILON.ln() / z.abs().ln()).ceil() as usize;
+  if horizon < n {
+    // 十分小さくなるまでの和で近似する
+    let mut zk = 1.0;
+    let mut sum = 0.0;
+    for v in c.iter().take(horizon) {
+      sum += zk * v;
+      zk *= z;
+    }
+    c[0] = sum;
+  } else {
+    // 短い場合は折り返しを考慮して厳密に求める
+    let zn = z.powi(n as i32 - 1);
+    let mut z2n = z.powi(2 * n as i32 - 3);
+    let mut zk = z;
+    let mut sum = c[0] + zn * c[n - 1];
+    for v in c.iter().take(n - 1).skip(1) {
+      sum += (zk + z2n) * v;
+      zk *= z;
+      z2n /= z;
+    }
+    c[0] = sum / (1.0 - zn * zn);
+  }
+  for k in 1..n {
+    c[k] += z * c[k - 1];
+  }
+  c[n - 1] = (z / (z * z - 1.0)) * (c[n - 1] + z * c[n - 2]);
+  for k in (0..n - 1).rev() {
+    c[k] = z * (c[k + 1] - c[k]);
+  }
+  for (v, c) in line.iter_mut().zip(c) {
+    *v = c as f32;
+  }
+}
+
+/// 1列分の画素を`scale`倍の間隔で`len`個に並べ直す
+fn resample_line(line: &[f32], len: usize, scale: f64, interpolation: Interpolation) -> Vec<f32> {
+  let n = line.len();
+  let mut coefficients = line.to_vec();
+  if interpolation == Interpolation::Bspline {
+    bspline_coefficients(&mut coefficients);
+  }
+  (0..len)
+    .map(|i| {
+      let x = (i as f64 * scale).min((n - 1) as f64);
+      match interpolation {
+        Interpolation::Nearest => line[(x.round() as usize).min(n - 1)],
+        Interpolation::Trilinear => {
+          let i0 = (x.floor() as usize).min(n - 1);
+          let i1 = (i0 + 1).min(n - 1);
+          let t = (x - i0 as f64) as f32;
+          line[i0] * (1.0 - t) + line[i1] * t
+        }
+        Interpolation::Bspline => {
+          let i0 = x.floor() as isize;
+          let t = x - i0 as f64;
+          let w = [
+            (1.0 - t).powi(3) / 6.0,
+            (4.0 - 6.0 * t * t + 3.0 * t.powi(3)) / 6.0,
+            (1.0 + 3.0 * t + 3.0 * t * t - 3.0 * t.powi(3)) / 6.0,
+            t.powi(3) / 6.0,
+          ];
+          w.iter()
+            .enumerate()
+            .map(|(k, w)| w * coefficients[mirror(i0 + k as isize - 1, n)] as f64)
+            .sum::<f64>() as f32
+        }
+      }
+    })
+    .collect()
+}
+
+/// 1つの軸の方向に画素の間隔を`from`から`to`に変える
+fn resample_axis(
+  data: &Array3<f32>,
+  axis: Axis,
+  from: f64,
+  to: f64,
+  interpolation: Interpolation,
+) -> Array3<f32> {
+  let len = resampled_len(data.len_of(axis), from, to);
+  let mut dim = data.raw_dim();
+  dim[axis.index()] = len;
+  let mut v = Array3::zeros(dim);
+  for (lane, mut new_lane) in data.lanes(axis).into_iter().zip(v.lanes_mut(axis)) {
+    let line = resample_line(&lane.to_vec(), len, to / from, interpolation);
+    for (item, value) in new_lane.iter_mut().zip(line) {
+      *item = value;
+    }
+  }
+  v
+}
+
+/// 画素の間隔が全ての方向で`spacing`になるように並べ直す
+///
+/// 原点と各軸の向きは変えず、OBJファイルの座標は元の画像の単位のままにする
+pub fn resample_isotropic(volume: &Volume, spacing: f64, interpolation: Interpolation) -> Volume {
+  let mut data = volume.data.mapv(|v| v as f32);
+  for (axis, from) in volume.geometry.spacing.iter().enumerate() {
+    // geometryの軸の順番はx, y, zで、配列の軸の順番はz, y, x
+    data = resample_axis(&data, Axis(2 - axis), *from, spacing, interpolation);
+  }
+  Volume {
+    data: data.mapv(|v| v.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16),
+    geometry: Geometry {
+      spacing: [spacing; 3],
+      voxel_spacing: Some(
+        volume
+          .geometry
+          .voxel_spacing
+          .unwrap_or(volume.geometry.spacing),
+      ),
+      ..volume.geometry
+    },
+  }
+}
+
+/// x, y, z方向にそれぞれ`factor`個ずつの画素の平均を取り、画素の数を減らす
+///
+/// 端で割り切れない部分は残っている画素だけで平均する
+/// 新しい画素の位置は平均した画素の中心とし、OBJファイルの座標は元の画像のものと変わらない
+pub fn downsample(volume: &Volume, factor: [usize; 3]) -> Volume {
+  let (height, rows, columns) = volume.data.dim();
+  let len = |n: usize, f: usize| n.div_ceil(f);
+  let dim = (
+    len(height, factor[2]),
+    len(rows, factor[1]),
+    len(columns, factor[0]),
+  );
+  let data = Array3::from_shape_fn(dim, |(z, y, x)| {
+    let block = volume.data.slice(s![
+      z * factor[2]..((z + 1) * factor[2]).min(height),
+      y * factor[1]..((y + 1) * factor[1]).min(rows),
+      x * factor[0]..((x + 1) * factor[0]).min(columns)
+    ]);
+    let sum = block.iter().map(|v| *v as i64).sum::<i64>();
+    (sum as f64 / block.len() as f64).round() as i16
+  });
+  let mut geometry = volume.geometry;
+  geometry.voxel_spacing = Some(geometry.voxel_spacing.unwrap_or(geometry.spacing));
+  for (axis, f) in factor.iter().enumerate() {
+    // 最初のブロックの中心までずらす
+    let shift = (*f as f64 - 1.0) / 2.0 * geometry.spacing[axis];
+    for (k, item) in geometry.origin.iter_mut().enumerate() {
+      *item += shift * geometry.direction[axis][k];
+    }
+    geometry.offset[axis] += shift;
+    geometry.spacing[axis] *= *f as f64;
+  }
+  Volume { data, geometry }
+}
+
+/// ラベルを最近傍補間で元の格子に戻す
+///
+/// `from`はラベルの画素の間隔、`to`と`dim`は戻す先の格子の画素の間隔と大きさ
+pub fn resample_labels(
+  labels: &Array3<u8>,
+  from: [f64; 3],
+  to: [f64; 3],
+  dim: (usize, usize, usize),
+) -> Array3<u8> {
+  let (height, rows, columns) = labels.dim();
+  let index = |i: usize, axis: usize, n: usize| {
+    ((i as f64 * to[axis] / from[axis]).round() as usize).min(n.saturating_sub(1))
+  };
+  Array3::from_shape_fn(dim, |(z, y, x)| {
+    labels[[index(z, 2, height), index(y, 1, rows), index(x, 0, columns)]]
+  })
+}
+
+#[cfg(test)]
+mod resample_test {
+  use crate::geometry::{CoordinateSystem, Geometry};
+  use crate::resample::*;
+  use ndarray::Array3;
+
+  #[test]
+  fn check_inverse() {
+    let m = [[2.0, 0.0, 1.0], [0.0, 1.0, 0.0], [0.0, 0.0, 4.0]];
+    let inv = inverse(m).unwrap();
+    let product =
+      [0, 1, 2].map(|i| [0, 1, 2].map(|j| (0..3).map(|k| m[i][k] * inv[k][j]).sum::<f64>()));
+    let identity = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+    for (row, expectation) in product.iter().zip(identity.iter()) {
+      for (v, e) in row.iter().zip(expectation.iter()) {
+        assert!((v - e).abs() < 1e-12);
+      }
+    }
+  }
+
+  #[test]
+  fn check_align_sheared() {
+    // z方向に1画素進むごとにy方向に1画素ずれている
+    let mut data = Array3::from_elem((3, 4, 2), 0i16);
+    for z in 0..3 {
+      data[[z, z, 0]] = 100;
+      data[[z, z, 1]] = 100;
+    }
+    let (sin, cos) = 45f64.to_radians().sin_cos();
+    let volume = Volume {
+      data,
+      geometry: Geometry {
+        spacing: [1.0, 1.0, 2f64.sqrt()],
+        origin: [0.0, 0.0, 0.0],
+        direction: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, sin, cos]],
+        ..Default::default()
+      },
+    };
+    assert!(!is_axis_aligned(&volume.geometry));
+    let aligned = align_to_axes(&volume);
+    assert!(is_axis_aligned(&aligned.geometry));
+    assert_eq!(aligned.data.dim(), (3, 6, 2));
+    assert!((aligned.geometry.spacing[2] - 1.0).abs() < 1e-9);
+    // 斜めに並んでいた画素がy方向に揃う
+    for z in 0..3 {
+      assert_eq!(aligned.data[[z, 2 * z, 0]], 100);
+      assert_eq!(aligned.data[[z, 2 * z + 1, 0]], 0);
+    }
+    assert_eq!(aligned.data[[0, 5, 0]], OUTSIDE_VALUE);
+  }
+
+  #[test]
+  fn check_resample_isotropic() {
+    let data = Array3::from_shape_fn((3, 2, 2), |(z, _, _)| z as i16 * 100);
+    let volume = Volume {
+      data,
+      geometry: Geometry {
+        spacing: [1.0, 1.0, 2.0],
+        ..Default::default()
+      },
+    };
+    for interpolation in [
+      Interpolation::Nearest,
+      Interpolation::Trilinear,
+      Interpolation::Bspline,
+    ] {
+      let resampled = resample_isotropic(&volume, 1.0, interpolation);
+      assert_eq!(resampled.data.dim(), (5, 2, 2));
+      assert_eq!(resampled.geometry.spacing, [1.0, 1.0, 1.0]);
+      // 元の画素の位置では値が変わらない
+      for z in 0..3 {
+        assert_eq!(resampled.data[[2 * z, 1, 1]], z as i16 * 100);
+      }
+    }
+    let resampled = resample_isotropic(&volume, 1.0, Interpolation::Trilinear);
+    assert_eq!(resampled.data[[1, 0, 0]], 50);
+    for system in [
+      CoordinateSystem::Voxel,
+      CoordinateSystem::Mm,
+      CoordinateSystem::Lps,
+    ] {
+      assert_eq!(
+        resampled.geometry.transform(system, (1.0, 0.0, 2.0)),
+        volume.geometry.transform(system, (1.0, 0.0, 1.0))
+      );
+    }
+    // 境界から離れた場所では直線を再現する
+    let volume = Volume {
+      data: Array3::from_shape_fn((8, 1, 1), |(z, _, _)| z as i16 * 100),
+      ..volume
+    };
+    let resampled = resample_isotropic(&volume, 1.0, Interpolation::Bspline);
+    assert!((resampled.data[[7, 0, 0]] - 350).abs() <= 1);
+  }
+
+  #[test]
+  fn check_downsample() {
+    let data = Array3::from_shape_fn((1, 2, 5), |(_, y, x)| (y * 10 + x) as i16);
+    let volume = Volume {
+      data,
+      geometry: Geometry {
+        spacing: [0.5, 0.5, 2.0],
+        ..Default::default()
+      },
+    };
+    let small = downsample(&volume, [2, 2, 1]);
+    assert_eq!(small.data.into_raw_vec(), vec![6, 8, 9]);
+    assert_eq!(small.geometry.spacing, [1.0, 1.0, 2.0]);
+    for system in [
+      CoordinateSystem::Voxel,
+      CoordinateSystem::Mm,
+      CoordinateSystem::Lps,
+    ] {
+      assert_eq!(
+        small.geometry.transform(system, (1.0, 0.0, 0.0)),
+        volume.geometry.transform(system, (2.5, 0.5, 0.0))
+      );
+    }
+  }
+
+  #[test]
+  fn check_resample_labels() {
+    let labels = Array3::from_shape_fn((5, 1, 1), |(z, _, _)| z as u8);
+    let back = resample_labels(&labels, [1.0, 1.0, 1.0], [1.0, 1.0, 2.0], (3, 1, 1));
+    assert_eq!(back.into_raw_vec(), vec![0, 2, 4]);
+  }
+}
@@ -0,0 +1,170 @@
+use crate::read_dicom::{slice_normal, SeriesInfo, Slice};
+use anyhow::{anyhow, Result};
+use std::fmt;
+
+/// 1つのシリーズに属するスライスの集まり
+#[derive(Debug, Clone, PartialEq)]
+pub struct Series {
+  pub info: SeriesInfo,
+  /// 体軸断面のシリーズかどうか
+  /// ImageOrientationPatientが無い場合は体軸断面とみなす
+  pub axial: bool,
+  pub slices: Vec<Slice>,
+}
+
+impl fmt::Display for Series {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let thickness = self
+      .info
+      .thickness
+      .map(|t| format!("{t}mm"))
+      .unwrap_or_else(|| "-".to_string());
+    write!(
+      f,
+      "{} '{}' modality={} kernel={} thickness={thickness} slices={}{}",
+      self.info.uid,
+      self.info.description,
+      self.info.modality,
+      self.info.kernel,
+      self.slices.len(),
+      if self.axial { "" } else { " (not axial)" }
+    )
+  }
+}
+
+/// 法線とz軸とのなす角のcosがこれ以上なら体軸断面とみなす
+const AXIAL_COS: f64 = 0.8;
+
+fn is_axial(slice: &Slice) -> bool {
+  slice
+    .orientation
+    .map(|o| slice_normal(&o)[2].abs() >= AXIAL_COS)
+    .unwrap_or(true)
+}
+
+/// SeriesInstanceUIDごとにスライスを分ける
+/// シリーズの順番は最初に出てきた順
+pub fn group_series(slices: Vec<Slice>) -> Vec<Series> {
+  let mut lst: Vec<Series> = Vec::new();
+  for slice in slices {
+    if let Some(series) = lst.iter_mut().find(|s| s.info.uid == slice.series.uid) {
+      series.axial = series.axial && is_axial(&slice);
+      series.slices.push(slice);
+    } else {
+      lst.push(Series {
+        info: slice.series.clone(),
+        axial: is_axial(&slice),
+        slices: vec![slice],
+      });
+    }
+  }
+  lst
+}
+
+/// 解析するシリーズを選ぶ
+///
+/// `key`にはシリーズの番号（1始まり）かSeriesInstanceUIDを与える
+/// 与えられなかった場合は体軸断面のCTのうち最もスライスが薄いものを選び、
+/// 同じ厚さならスライスの枚数が多いものを選ぶ
+pub fn select_series(series_lst: Vec<Series>, key: Option<&str>) -> Result<Series> {
+  if let Some(key) = key {
+    let index = key
+      .parse::<usize>()
+      .ok()
+      .and_then(|n| n.checked_sub(1))
+      .filter(|n| *n < series_lst.len());
+    return match index {
+      Some(n) => Ok(series_lst.into_iter().nth(n).unwrap()),
+      None => series_lst
+        .into_iter()
+        .find(|s| s.info.uid == key)
+        .ok_or_else(|| anyhow!("error: series '{key}' not found")),
+    };
+  }
+  series_lst
+    .into_iter()
+    .filter(|s| s.axial && (s.info.modality.is_empty() || s.info.modality == "CT"))
+    .min_by(|s1, s2| {
+      let t1 = s1.info.thickness.unwrap_or(f64::INFINITY);
+      let t2 = s2.info.thickness.unwrap_or(f64::INFINITY);
+      t1.total_cmp(&t2)
+        .then_with(|| s2.slices.len().cmp(&s1.slices.len()))
+    })
+    .ok_or_else(|| anyhow!("error: no axial CT series found"))
+}
+
+#[cfg(test)]
+mod series_test {
+  use crate::read_dicom::*;
+  use crate::series::*;
+  use std::path::PathBuf;
+
+  fn slice(uid: &str, modality: &str, thickness: f64, orientation: [f64; 6]) -> Slice {
+    Slice {
+      path: PathBuf::new(),
+      filename: String::new(),
+      frame: None,
+      series: SeriesInfo {
+        uid: uid.to_string(),
+        modality: modality.to_string(),
+        thickness: Some(thickness),
+        ..Default::default()
+      },
+      rows: 1,
+      columns: 1,
+      position: None,
+      orientation: Some(orientation),
+      instance_number: None,
+      pixel_spacing: None,
+      slice_spacing: None,
+      rescale: None,
+      pixels: Vec::new(),
+    }
+  }
+
+  const AXIAL: [f64; 6] = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0];
+  const CORONAL: [f64; 6] = [1.0, 0.0, 0.0, 0.0, 0.0, -1.0];
+
+  fn series_lst() -> Vec<Series> {
+    group_series(vec![
+      slice("scout", "CT", 0.5, CORONAL),
+      slice("soft", "CT", 5.0, AXIAL),
+      slice("lung", "CT", 1.0, AXIAL),
+      slice("soft", "CT", 5.0, AXIAL),
+      slice("lung", "CT", 1.0, AXIAL),
+      slice("dose", "OT", 0.1, AXIAL),
+    ])
+  }
+
+  #[test]
+  fn check_group_series() {
+    let lst = series_lst();
+    let uids = lst
+      .iter()
+      .map(|s| (s.info.uid.as_str(), s.slices.len(), s.axial))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      uids,
+      vec![
+        ("scout", 1, false),
+        ("soft", 2, true),
+        ("lung", 2, true),
+        ("dose", 1, true)
+      ]
+    );
+  }
+
+  #[test]
+  fn check_select_series() {
+    assert_eq!(select_series(series_lst(), None).unwrap().info.uid, "lung");
+    assert_eq!(
+      select_series(series_lst(), Some("2")).unwrap().info.uid,
+      "soft"
+    );
+    assert_eq!(
+      select_series(series_lst(), Some("scout")).unwrap().info.uid,
+      "scout"
+    );
+    assert!(select_series(series_lst(), Some("unknown")).is_err());
+  }
+}
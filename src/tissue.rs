@@ -0,0 +1,225 @@
+use anyhow::{anyhow, Context, Result};
+use ndarray::{Array3, Zip};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::Path;
+use tokio::fs;
+
+/// 名前の付いた部位（グループ）の設定
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tissue {
+  /// 部位の名前（出力するファイルの名前に使う）
+  pub name: String,
+  /// 分類するときの重心の初期値（HU）
+  #[serde(default)]
+  pub initial: Option<i16>,
+  /// 分類せずにこの部位とするCT値の範囲（両端を含む）
+  #[serde(default)]
+  pub range: Option<[i16; 2]>,
+  /// 断面画像で塗る色（RGB）
+  /// 与えなかった場合は番号から決める
+  #[serde(default)]
+  pub color: Option<[u8; 3]>,
+  /// OBJファイルを生成するかどうか
+  #[serde(default = "default_mesh")]
+  pub mesh: bool,
+  /// ノイズ除去の回数
+  /// 与えなかった場合は`--noise-removal`の値を使う
+  #[serde(default)]
+  pub noise_removal: Option<usize>,
+}
+
+fn default_mesh() -> bool {
+  true
+}
+
+impl Tissue {
+  /// 番号を名前とし、初期値だけを与えた部位
+  /// 0番目のグループはOBJファイルを生成しない
+  pub fn numbered(i: usize, initial: i16) -> Self {
+    Tissue {
+      name: i.to_string(),
+      initial: Some(initial),
+      range: None,
+      color: None,
+      mesh: i != 0,
+      noise_removal: None,
+    }
+  }
+
+  /// CT値`value`が固定された範囲に入っているかどうか
+  pub fn contains(&self, value: i16) -> bool {
+    self
+      .range
+      .is_some_and(|[min, max]| min <= value && value <= max)
+  }
+}
+
+/// CT値が固定された範囲に入っている画素をその部位の番号にする
+/// 複数の範囲に入る場合は番号が小さい部位とし、`mask`が`false`の画素とどの範囲にも入らない画素はそのまま残す
+pub fn fill_fixed(
+  labels: &mut Array3<u8>,
+  data: &Array3<i16>,
+  mask: Option<&Array3<bool>>,
+  tissues: &[Tissue],
+) {
+  Zip::indexed(labels).and(data).for_each(|p, label, value| {
+    let inside = mask.is_none_or(|mask| mask[p]);
+    if let Some(i) = tissues.iter().position(|tissue| tissue.contains(*value)) {
+      if inside {
+        *label = i as u8;
+      }
+    }
+  });
+}
+
+/// 部位の設定ファイルの中身
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TissueConfig {
+  tissues: Vec<Tissue>,
+}
+
+/// 設定の内容が正しいかを確かめる
+fn validate(tissues: &[Tissue]) -> Result<()> {
+  if tissues.is_empty() {
+    return Err(anyhow!("error: no tissue is defined"));
+  }
+  let mut names = HashSet::new();
+  for tissue in tissues.iter() {
+    let name = &tissue.name;
+    if name.is_empty() || name.contains(['/', '\\']) {
+      return Err(anyhow!("error: invalid tissue name '{name}'"));
+    }
+    if !names.insert(name) {
+      return Err(anyhow!("error: tissue '{name}' is defined twice"));
+    }
+    match (tissue.initial, tissue.range) {
+      (Some(_), None) => (),
+      (None, Some([min, max])) if min <= max => (),
+      (None, Some(_)) => return Err(anyhow!("error: tissue '{name}' has an empty range")),
+      _ => {
+        return Err(anyhow!(
+          "error: tissue '{name}' needs either 'initial' or 'range'"
+        ))
+      }
+    }
+  }
+  Ok(())
+}
+
+/// JSONかTOMLで書かれた部位の設定を読む
+/// `.json`と`.toml`以外の拡張子の場合はJSONとして読めなければTOMLとして読む
+pub fn parse_tissues(text: &str, extension: &str) -> Result<Vec<Tissue>> {
+  let config = match extension {
+    "json" => serde_json::from_str::<TissueConfig>(text)?,
+    "toml" => toml::from_str::<TissueConfig>(text)?,
+    _ => match serde_json::from_str::<TissueConfig>(text) {
+      Ok(config) => config,
+      Err(_) => toml::from_str::<TissueConfig>(text)?,
+    },
+  };
+  validate(&config.tissues)?;
+  Ok(config.tissues)
+}
+
+/// 部位の設定ファイルを読み込む
+pub async fn read_tissues(path: &Path) -> Result<Vec<Tissue>> {
+  let text = fs::read_to_string(path)
+    .await
+    .with_context(|| format!("error: cannot read {}", path.display()))?;
+  let extension = path
+    .extension()
+    .map(|ext| ext.to_string_lossy().to_lowercase())
+    .unwrap_or_default();
+  parse_tissues(&text, &extension).with_context(|| format!("error: in {}", path.display()))
+}
+
+#[cfg(test)]
+mod tissue_test {
+  use crate::filter::{closing_block, opening_block, refilter_group, NO_GROUP};
+  use crate::tissue::*;
+
+  #[test]
+  fn check_parse_toml() {
+    let text = r#"
+[[tissues]]
+name = "air"
+initial = -990
+mesh = false
+
+[[tissues]]
+name = "bone"
+range = [300, 3000]
+color = [255, 255, 255]
+noise_removal = 2
+"#;
+    let tissues = parse_tissues(text, "toml").unwrap();
+    assert_eq!(tissues.len(), 2);
+    assert_eq!(tissues[0].initial, Some(-990));
+    assert!(!tissues[0].mesh);
+    assert!(tissues[1].mesh);
+    assert!(tissues[1].contains(300));
+    assert!(!tissues[1].contains(299));
+    assert_eq!(tissues[1].noise_removal, Some(2));
+  }
+
+  #[test]
+  fn check_parse_json() {
+    let text = r#"{"tissues": [{"name": "lung", "initial": -750, "color": [0, 0, 255]}]}"#;
+    let tissues = parse_tissues(text, "").unwrap();
+    assert_eq!(tissues[0].name, "lung");
+    assert_eq!(tissues[0].color, Some([0, 0, 255]));
+  }
+
+  #[test]
+  fn check_validate() {
+    let text = r#"{"tissues": [{"name": "a", "initial": 0}, {"name": "a", "initial": 1}]}"#;
+    assert!(parse_tissues(text, "json").is_err());
+    let text = r#"{"tissues": [{"name": "a"}]}"#;
+    assert!(parse_tissues(text, "json").is_err());
+    let text = r#"{"tissues": [{"name": "a/b", "initial": 0}]}"#;
+    assert!(parse_tissues(text, "json").is_err());
+  }
+
+  #[tokio::test]
+  async fn check_fill_fixed_filtered() {
+    // 範囲が固定された部位には、ノイズ除去の後も範囲内の画素だけが入る
+    let text = r#"
+      [[tissues]]
+      name = "bone"
+      range = [200, 3000]
+      noise_removal = 2
+
+      [[tissues]]
+      name = "air"
+      range = [-1100, -900]
+    "#;
+    let tissues = parse_tissues(text, "toml").unwrap();
+    // 中心に穴の開いた骨の塊と、孤立した空気の1画素
+    let mut data = Array3::from_elem((5, 5, 5), 0i16);
+    for z in 1..4 {
+      for y in 1..4 {
+        for x in 1..4 {
+          data[[z, y, x]] = 300;
+        }
+      }
+    }
+    data[[2, 2, 2]] = 0;
+    data[[0, 0, 4]] = -1000;
+    let mut labels_raw = Array3::from_elem(data.dim(), NO_GROUP);
+    fill_fixed(&mut labels_raw, &data, None, &tissues);
+    assert_eq!(labels_raw[[1, 1, 1]], 0);
+    assert_eq!(labels_raw[[0, 0, 4]], 1);
+    let labels = opening_block(&labels_raw, 1).await;
+    let mut labels = closing_block(&labels, 1).await;
+    refilter_group(&labels_raw, &mut labels, 0, 2).await;
+    assert_eq!(labels[[2, 2, 2]], NO_GROUP);
+    for (label, value) in labels.iter().zip(data.iter()) {
+      if let Some(tissue) = tissues.get(*label as usize) {
+        assert!(tissue.contains(*value));
+      }
+    }
+  }
+}
@@ -0,0 +1,269 @@
+use crate::geometry::{CoordinateSystem, Geometry};
+use crate::read_nifti;
+use anyhow::{anyhow, Result};
+use ndarray::{s, Array3};
+use std::path::Path;
+
+/// 読み込んだCT画像全体のデータ
+#[derive(Debug, Clone, PartialEq)]
+pub struct Volume {
+  /// `[[z, y, x]]`で添字を与えるCT値
+  pub data: Array3<i16>,
+  pub geometry: Geometry,
+}
+
+impl Volume {
+  /// x, y, zの順に添字が速く変わるように並べたCT値からVolumeを作る
+  pub fn new(
+    rows: usize,
+    columns: usize,
+    height: usize,
+    data: Vec<i16>,
+    geometry: Geometry,
+  ) -> Result<Self> {
+    let data = Array3::from_shape_vec((height, rows, columns), data)?;
+    Ok(Volume { data, geometry })
+  }
+
+  /// y方向の大きさ
+  pub fn rows(&self) -> usize {
+    self.data.dim().1
+  }
+
+  /// x方向の大きさ
+  pub fn columns(&self) -> usize {
+    self.data.dim().2
+  }
+
+  /// z方向の大きさ
+  pub fn height(&self) -> usize {
+    self.data.dim().0
+  }
+
+  /// `system`で表された2つの角`start`と`end`を含む範囲を、x, y, zの順の画素の添字の範囲に変換する
+  /// 与えなかった角は画像の端とする
+  pub fn crop_bounds(
+    &self,
+    system: CoordinateSystem,
+    start: Option<[f64; 3]>,
+    end: Option<[f64; 3]>,
+  ) -> Result<([usize; 3], [usize; 3])> {
+    let dim = [self.columns(), self.rows(), self.height()];
+    let to_index = |p: Option<[f64; 3]>, default: [f64; 3]| match p {
+      Some(p) => self
+        .geometry
+        .index_of(system, p)
+        .ok_or_else(|| anyhow!("error: cannot convert {p:?} to a voxel index")),
+      None => Ok(default),
+    };
+    let last = dim.map(|n| n as f64 - 1.0);
+    let a = to_index(start, [0.0; 3])?;
+    let b = to_index(end, last)?;
+    // 片方の角だけを与えた場合、その角は画像の中になければならない
+    let given = match (start.is_some(), end.is_some()) {
+      (true, false) => Some(a),
+      (false, true) => Some(b),
+      _ => None,
+    };
+    if let Some(p) = given {
+      if (0..3).any(|axis| p[axis] < -0.5 || p[axis] > last[axis] + 0.5) {
+        return Err(anyhow!(
+          "error: the corner of the range is outside the image"
+        ));
+      }
+    }
+    let mut lower = [0; 3];
+    let mut upper = [0; 3];
+    for axis in 0..3 {
+      // 画素の中心が範囲に含まれるものを残す
+      let l = (a[axis].min(b[axis]) - 1e-6).ceil().max(0.0);
+      let u = (a[axis].max(b[axis]) + 1e-6).floor().min(last[axis]);
+      if l > u {
+        return Err(anyhow!(
+          "error: the range does not overlap the image in the {} direction",
+          ["x", "y", "z"][axis]
+        ));
+      }
+      lower[axis] = l as usize;
+      upper[axis] = u as usize;
+    }
+    Ok((lower, upper))
+  }
+
+  /// x, y, zの順の添字で与えた`start`から`end`まで（両端を含む）を切り出す
+  /// 切り出した後も患者座標とOBJファイルの座標が変わらないように位置を調整する
+  pub fn crop(&self, start: [usize; 3], end: [usize; 3]) -> Volume {
+    let data = self
+      .data
+      .slice(s![start[2]..=end[2], start[1]..=end[1], start[0]..=end[0]])
+      .to_owned();
+    let mut geometry = self.geometry;
+    for (axis, i) in start.iter().enumerate() {
+      let length = *i as f64 * geometry.spacing[axis];
+      for (k, item) in geometry.origin.iter_mut().enumerate() {
+        *item += length * geometry.direction[axis][k];
+      }
+      geometry.offset[axis] += length;
+    }
+    Volume { data, geometry }
+  }
+}
+
+/// 入力されたパスの形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+  /// DICOMのフォルダかDICOMDIR
+  Dicom,
+  /// NIfTI-1（.nii, .nii.gz）
+  Nifti,
+  /// MetaImage（.mhd, .mha）
+  MetaImage,
+  /// NRRD（.nrrd, .nhdr）
+  Nrrd,
+}
+
+/// 拡張子から入力の形式を判定する
+/// 拡張子で分からないファイルは先頭の数バイトから判定する
+pub fn detect_format(path: &Path) -> InputFormat {
+  let name = path
+    .file_name()
+    .map(|name| name.to_string_lossy().to_lowercase())
+    .unwrap_or_default();
+  if name.ends_with(".nii") || name.ends_with(".nii.gz") {
+    return InputFormat::Nifti;
+  }
+  if name.ends_with(".mhd") || name.ends_with(".mha") {
+    return InputFormat::MetaImage;
+  }
+  if name.ends_with(".nrrd") || name.ends_with(".nhdr") {
+    return InputFormat::Nrrd;
+  }
+  if !path.is_file() || name == "dicomdir" {
+    return InputFormat::Dicom;
+  }
+  let mut head = [0u8; 348];
+  let len = std::fs::File::open(path)
+    .and_then(|mut f| std::io::Read::read(&mut f, &mut head))
+    .unwrap_or(0);
+  let head = &head[..len];
+  if head.starts_with(b"NRRD") {
+    InputFormat::Nrrd
+  } else if head.starts_with(b"ObjectType") || head.starts_with(b"NDims") {
+    InputFormat::MetaImage
+  } else if read_nifti::is_nifti_header(head) {
+    InputFormat::Nifti
+  } else {
+    InputFormat::Dicom
+  }
+}
+
+/// ファイルに保存されている画素値の型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScalarType {
+  U8,
+  I8,
+  U16,
+  I16,
+  U32,
+  I32,
+  F32,
+  F64,
+}
+
+impl ScalarType {
+  /// 1つの値のバイト数
+  pub fn size(&self) -> usize {
+    match self {
+      ScalarType::U8 | ScalarType::I8 => 1,
+      ScalarType::U16 | ScalarType::I16 => 2,
+      ScalarType::U32 | ScalarType::I32 | ScalarType::F32 => 4,
+      ScalarType::F64 => 8,
+    }
+  }
+}
+
+/// バイト列を`count`個の値として読み、`slope`倍して`inter`を足したCT値のリストにする
+pub fn decode_scalars(
+  bytes: &[u8],
+  scalar_type: ScalarType,
+  big_endian: bool,
+  count: usize,
+  slope: f64,
+  inter: f64,
+) -> Result<Vec<i16>> {
+  let size = scalar_type.size();
+  if bytes.len() < count * size {
+    return Err(anyhow!(
+      "error: image data is too short ({} bytes for {count} values)",
+      bytes.len()
+    ));
+  }
+  let v = bytes[..count * size]
+    .chunks_exact(size)
+    .map(|chunk| {
+      let mut b = [0u8; 8];
+      b[..size].copy_from_slice(chunk);
+      if big_endian {
+        b[..size].reverse();
+      }
+      let v = match scalar_type {
+        ScalarType::U8 => b[0] as f64,
+        ScalarType::I8 => b[0] as i8 as f64,
+        ScalarType::U16 => u16::from_le_bytes([b[0], b[1]]) as f64,
+        ScalarType::I16 => i16::from_le_bytes([b[0], b[1]]) as f64,
+        ScalarType::U32 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+        ScalarType::I32 => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+        ScalarType::F32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+        ScalarType::F64 => f64::from_le_bytes(b),
+      };
+      (v * slope + inter)
+        .round()
+        .clamp(i16::MIN as f64, i16::MAX as f64) as i16
+    })
+    .collect();
+  Ok(v)
+}
+
+#[cfg(test)]
+mod volume_test {
+  use crate::volume::*;
+
+  #[test]
+  fn check_crop() {
+    let geometry = Geometry {
+      spacing: [0.5, 0.5, 2.0],
+      origin: [-100.0, -50.0, 10.0],
+      ..Default::default()
+    };
+    let data = (0..4 * 3 * 2).collect::<Vec<i16>>();
+    let volume = Volume::new(3, 4, 2, data, geometry).unwrap();
+    let (start, end) = volume
+      .crop_bounds(CoordinateSystem::Mm, Some([0.4, 1.0, 0.0]), None)
+      .unwrap();
+    assert_eq!((start, end), ([1, 2, 0], [3, 2, 1]));
+    let cropped = volume.crop(start, end);
+    assert_eq!(cropped.data.dim(), (2, 1, 3));
+    assert_eq!(cropped.data[[1, 0, 0]], volume.data[[1, 2, 1]]);
+    let p = (0.0, 0.0, 1.0);
+    for system in [
+      CoordinateSystem::Voxel,
+      CoordinateSystem::Mm,
+      CoordinateSystem::Lps,
+    ] {
+      assert_eq!(
+        cropped.geometry.transform(system, p),
+        volume.geometry.transform(system, (1.0, 2.0, 1.0))
+      );
+    }
+    assert!(volume
+      .crop_bounds(
+        CoordinateSystem::Voxel,
+        Some([5.0, 0.0, 0.0]),
+        Some([6.0, 2.0, 1.0])
+      )
+      .is_err());
+    assert!(volume
+      .crop_bounds(CoordinateSystem::Voxel, Some([5.0, 0.0, 0.0]), None)
+      .is_err());
+  }
+}
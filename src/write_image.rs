@@ -1,53 +1,42 @@
-use crate::{Data, Point};
+use crate::filter::{NO_GROUP, OUTSIDE};
 use image::{Rgb, RgbImage};
-use tokio_stream::StreamExt;
+use ndarray::ArrayView2;
 
-#[allow(dead_code)]
-
-pub async fn data_to_img(w: u32, h: u32, data_lst: &[Vec<Data>]) -> RgbImage {
-  let mut img = RgbImage::new(w, h);
-  for (i, data) in data_lst.iter().enumerate() {
-    let color = if i == 0 {
-      Rgb([0, 255, 0])
-    } else if i == 1 {
-      Rgb([0, 0, 255])
-    } else if i == 2 {
-      Rgb([0, 255, 255])
-    } else if i == 3 {
-      Rgb([255, 0, 255])
-    } else if i == 4 {
-      Rgb([255, 255, 0])
-    } else {
-      Rgb([255, 0, 0])
-    };
-    let mut data = tokio_stream::iter(data);
-    while let Some(d) = data.next().await {
-      img.put_pixel(d.point.x as u32, d.point.y as u32, color);
-    }
+fn group_color(i: usize) -> Rgb<u8> {
+  if i == 0 {
+    Rgb([0, 255, 0])
+  } else if i == 1 {
+    Rgb([0, 0, 255])
+  } else if i == 2 {
+    Rgb([0, 255, 255])
+  } else if i == 3 {
+    Rgb([255, 0, 255])
+  } else if i == 4 {
+    Rgb([255, 255, 0])
+  } else {
+    Rgb([255, 0, 0])
   }
-  img
 }
 
-pub async fn point_to_img(w: u32, h: u32, point_lst: &[Vec<Point>]) -> RgbImage {
-  let mut img = RgbImage::new(w, h);
-  for (i, point) in point_lst.iter().enumerate() {
-    let color = if i == 0 {
-      Rgb([0, 255, 0])
-    } else if i == 1 {
-      Rgb([0, 0, 255])
-    } else if i == 2 {
-      Rgb([0, 255, 255])
-    } else if i == 3 {
-      Rgb([255, 0, 255])
-    } else if i == 4 {
-      Rgb([255, 255, 0])
-    } else {
-      Rgb([255, 0, 0])
-    };
-    let mut point = tokio_stream::iter(point);
-    while let Some(p) = point.next().await {
-      img.put_pixel(p.x as u32, p.y as u32, color);
+/// `[[y, x]]`で添字を与える1枚分のラベルから画像を生成する
+/// `group`を与えた場合はそのグループだけを塗る
+/// `colors`で色を与えていないグループは番号から色を決める
+pub async fn labels_to_img(
+  labels: ArrayView2<'_, u8>,
+  group: Option<usize>,
+  colors: &[Option<[u8; 3]>],
+) -> RgbImage {
+  let (h, w) = labels.dim();
+  let mut img = RgbImage::new(w as u32, h as u32);
+  for ((y, x), label) in labels.indexed_iter() {
+    if *label == NO_GROUP || *label == OUTSIDE || group.is_some_and(|g| g != *label as usize) {
+      continue;
     }
+    let color = match colors.get(*label as usize) {
+      Some(Some(color)) => Rgb(*color),
+      _ => group_color(*label as usize),
+    };
+    img.put_pixel(x as u32, y as u32, color);
   }
   img
 }
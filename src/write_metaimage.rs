@@ -0,0 +1,59 @@
+use crate::geometry::Geometry;
+use anyhow::Result;
+use ndarray::Array3;
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+
+/// MetaImageのヘッダを生成する
+/// データはヘッダに続けて書く（ElementDataFile = LOCAL）
+fn header(dim: (usize, usize, usize), geometry: &Geometry) -> String {
+  let (height, rows, columns) = dim;
+  let join = |v: &[f64]| {
+    v.iter()
+      .map(|v| v.to_string())
+      .collect::<Vec<_>>()
+      .join(" ")
+  };
+  format!(
+    "ObjectType = Image\nNDims = 3\nBinaryData = True\nBinaryDataByteOrderMSB = False\nCompressedData = False\nTransformMatrix = {}\nOffset = {}\nElementSpacing = {}\nDimSize = {columns} {rows} {height}\nElementType = MET_UCHAR\nElementDataFile = LOCAL\n",
+    join(geometry.direction.concat().as_slice()),
+    join(&geometry.origin),
+    join(&geometry.spacing),
+  )
+}
+
+/// `[[z, y, x]]`で添字を与える値を1つのMetaImageファイル（.mha）に書き出す
+pub async fn write_metaimage(path: &Path, data: &Array3<u8>, geometry: &Geometry) -> Result<()> {
+  let mut buf = File::create(path).await?;
+  buf
+    .write_all(header(data.dim(), geometry).as_bytes())
+    .await?;
+  // 標準の並び順ならx, y, zの順に添字が速く変わる
+  let bytes = data.iter().copied().collect::<Vec<_>>();
+  buf.write_all(&bytes).await?;
+  buf.flush().await?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod write_metaimage_test {
+  use crate::read_metaimage::read_metaimage;
+  use crate::write_metaimage::*;
+
+  #[tokio::test]
+  async fn check_write_metaimage() {
+    let geometry = Geometry {
+      spacing: [0.5, 0.75, 2.0],
+      origin: [-100.0, -50.0, 10.0],
+      ..Default::default()
+    };
+    let data = Array3::from_shape_fn((2, 3, 4), |(z, y, x)| (z * 12 + y * 4 + x) as u8);
+    let path = std::env::temp_dir().join("vlung_analysis_write_metaimage_test.mha");
+    write_metaimage(&path, &data, &geometry).await.unwrap();
+    let volume = read_metaimage(&path).await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(volume.geometry, geometry);
+    assert_eq!(volume.data, data.mapv(|v| v as i16));
+  }
+}